@@ -0,0 +1,849 @@
+use std::{collections::BTreeMap, error, fmt};
+
+use crate::{loader::ObjectImage, memory::LC3_MEMORY_SIZE};
+
+/// An assembled image together with the labels it defines.
+pub struct Program {
+    image: ObjectImage,
+    symbols: BTreeMap<String, u16>,
+}
+
+impl Program {
+    pub fn image(&self) -> &ObjectImage {
+        &self.image
+    }
+
+    pub fn into_image(self) -> ObjectImage {
+        self.image
+    }
+
+    pub fn symbols(&self) -> &BTreeMap<String, u16> {
+        &self.symbols
+    }
+
+    pub fn symbol(&self, label: &str) -> Option<u16> {
+        self.symbols.get(label).copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    line: usize,
+    message: String,
+}
+
+impl AsmError {
+    fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+
+    /// One-based source line the error was reported on.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Register(u16),
+    Number(i32),
+    Label(String),
+    Str(String),
+}
+
+struct Statement {
+    line: usize,
+    address: u16,
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+const MNEMONICS: [&str; 27] = [
+    "ADD", "AND", "NOT", "JMP", "RET", "JSR", "JSRR", "LD", "LDI", "LDR", "LEA", "ST", "STI",
+    "STR", "TRAP", "RTI", "GETC", "OUT", "PUTS", "IN", "PUTSP", "HALT", ".ORIG", ".FILL", ".BLKW",
+    ".STRINGZ", ".END",
+];
+
+/// Assembles LC-3 source text into a loadable image.
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    let (statements, symbols, origin) = first_pass(source)?;
+
+    let mut words = Vec::new();
+    for statement in &statements {
+        encode(statement, &symbols, &mut words)?;
+    }
+
+    Ok(Program {
+        image: ObjectImage::new(origin, words)
+            .expect("the first pass keeps every statement inside memory"),
+        symbols,
+    })
+}
+
+type FirstPass = (Vec<Statement>, BTreeMap<String, u16>, u16);
+
+fn first_pass(source: &str) -> Result<FirstPass, AsmError> {
+    let mut statements = Vec::new();
+    let mut symbols = BTreeMap::new();
+    let mut origin: Option<u16> = None;
+    let mut location: usize = 0;
+    let mut last_line = 0;
+
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        last_line = line;
+        let mut tokens = tokenize(text, line)?.into_iter().peekable();
+
+        let mut label = None;
+        if let Some(Token::Word(word)) = tokens.peek() {
+            if !is_mnemonic(word) {
+                let name = word.strip_suffix(':').unwrap_or(word).to_string();
+                if !is_identifier(&name) {
+                    return Err(AsmError::new(line, format!("invalid label '{}'", word)));
+                }
+                label = Some(name);
+                tokens.next();
+            }
+        }
+
+        let mnemonic = match tokens.next() {
+            Some(Token::Word(word)) => word.to_ascii_uppercase(),
+            Some(Token::Str(_)) => {
+                return Err(AsmError::new(line, "unexpected string literal"));
+            }
+            None => {
+                if let Some(label) = label {
+                    if origin.is_none() {
+                        return Err(AsmError::new(line, "label before .ORIG"));
+                    }
+                    define(&mut symbols, label, location, line)?;
+                }
+                continue;
+            }
+        };
+        if !is_mnemonic(&mnemonic) {
+            return Err(AsmError::new(
+                line,
+                format!("unknown instruction '{}'", mnemonic),
+            ));
+        }
+
+        let operands = tokens
+            .map(|token| parse_operand(token, line))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if mnemonic == ".ORIG" {
+            if origin.is_some() {
+                return Err(AsmError::new(line, "duplicate .ORIG"));
+            }
+            if label.is_some() {
+                return Err(AsmError::new(line, "label on .ORIG"));
+            }
+            let address = match operands.as_slice() {
+                [Operand::Number(value)] => unsigned(*value, 16, line)?,
+                _ => return Err(AsmError::new(line, ".ORIG expects an address")),
+            };
+            origin = Some(address);
+            location = address as usize;
+            continue;
+        }
+
+        if origin.is_none() {
+            return Err(AsmError::new(line, format!("{} before .ORIG", mnemonic)));
+        }
+
+        if mnemonic == ".END" {
+            if let Some(label) = label {
+                define(&mut symbols, label, location, line)?;
+            }
+            return Ok((statements, symbols, origin.unwrap_or_default()));
+        }
+
+        if let Some(label) = label {
+            define(&mut symbols, label, location, line)?;
+        }
+
+        let size = match mnemonic.as_str() {
+            ".BLKW" => match operands.as_slice() {
+                [Operand::Number(count)] => unsigned(*count, 16, line)? as usize,
+                _ => return Err(AsmError::new(line, ".BLKW expects a word count")),
+            },
+            ".STRINGZ" => match operands.as_slice() {
+                [Operand::Str(string)] => string.chars().count() + 1,
+                _ => return Err(AsmError::new(line, ".STRINGZ expects a string literal")),
+            },
+            _ => 1,
+        };
+
+        if location + size > LC3_MEMORY_SIZE {
+            return Err(AsmError::new(line, "program runs past the end of memory"));
+        }
+
+        statements.push(Statement {
+            line,
+            address: location as u16,
+            mnemonic,
+            operands,
+        });
+        location += size;
+    }
+
+    match origin {
+        Some(_) => Err(AsmError::new(last_line, "missing .END")),
+        None => Err(AsmError::new(last_line, "missing .ORIG")),
+    }
+}
+
+fn define(
+    symbols: &mut BTreeMap<String, u16>,
+    label: String,
+    location: usize,
+    line: usize,
+) -> Result<(), AsmError> {
+    if location >= LC3_MEMORY_SIZE {
+        return Err(AsmError::new(line, "label past the end of memory"));
+    }
+    if symbols.contains_key(&label) {
+        return Err(AsmError::new(line, format!("duplicate label '{}'", label)));
+    }
+    symbols.insert(label, location as u16);
+    Ok(())
+}
+
+fn encode(
+    statement: &Statement,
+    symbols: &BTreeMap<String, u16>,
+    words: &mut Vec<u16>,
+) -> Result<(), AsmError> {
+    let line = statement.line;
+    let operands = statement.operands.as_slice();
+    let mnemonic = statement.mnemonic.as_str();
+
+    let expect = |count: usize| -> Result<(), AsmError> {
+        if operands.len() == count {
+            Ok(())
+        } else {
+            Err(AsmError::new(
+                line,
+                format!(
+                    "{} expects {} operand(s), found {}",
+                    mnemonic,
+                    count,
+                    operands.len()
+                ),
+            ))
+        }
+    };
+    let register = |index: usize| -> Result<u16, AsmError> {
+        match &operands[index] {
+            Operand::Register(r) => Ok(*r),
+            _ => Err(AsmError::new(
+                line,
+                format!("{} operand {} must be a register", mnemonic, index + 1),
+            )),
+        }
+    };
+    let offset = |index: usize, bits: u32| -> Result<u16, AsmError> {
+        let value = match &operands[index] {
+            Operand::Number(value) => *value,
+            Operand::Label(label) => {
+                let target = resolve(symbols, label, line)?;
+                target as i32 - (statement.address as i32 + 1)
+            }
+            _ => {
+                return Err(AsmError::new(
+                    line,
+                    format!(
+                        "{} operand {} must be a label or offset",
+                        mnemonic,
+                        index + 1
+                    ),
+                ))
+            }
+        };
+        signed(value, bits, line)
+    };
+
+    let word = match mnemonic {
+        "ADD" | "AND" => {
+            expect(3)?;
+            let opcode = if mnemonic == "ADD" { 0x1000 } else { 0x5000 };
+            let base = opcode | register(0)? << 9 | register(1)? << 6;
+            match &operands[2] {
+                Operand::Register(sr2) => base | sr2,
+                Operand::Number(imm) => base | 0x20 | signed(*imm, 5, line)?,
+                _ => {
+                    return Err(AsmError::new(
+                        line,
+                        format!("{} operand 3 must be a register or immediate", mnemonic),
+                    ))
+                }
+            }
+        }
+        "NOT" => {
+            expect(2)?;
+            0x9000 | register(0)? << 9 | register(1)? << 6 | 0x3F
+        }
+        "JMP" => {
+            expect(1)?;
+            0xC000 | register(0)? << 6
+        }
+        "RET" => {
+            expect(0)?;
+            0xC1C0
+        }
+        "JSR" => {
+            expect(1)?;
+            0x4800 | offset(0, 11)?
+        }
+        "JSRR" => {
+            expect(1)?;
+            0x4000 | register(0)? << 6
+        }
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect(2)?;
+            let opcode = match mnemonic {
+                "LD" => 0x2000,
+                "LDI" => 0xA000,
+                "LEA" => 0xE000,
+                "ST" => 0x3000,
+                _ => 0xB000,
+            };
+            opcode | register(0)? << 9 | offset(1, 9)?
+        }
+        "LDR" | "STR" => {
+            expect(3)?;
+            let opcode = if mnemonic == "LDR" { 0x6000 } else { 0x7000 };
+            let imm = match &operands[2] {
+                Operand::Number(value) => signed(*value, 6, line)?,
+                _ => {
+                    return Err(AsmError::new(
+                        line,
+                        format!("{} operand 3 must be an offset", mnemonic),
+                    ))
+                }
+            };
+            opcode | register(0)? << 9 | register(1)? << 6 | imm
+        }
+        "TRAP" => {
+            expect(1)?;
+            match &operands[0] {
+                Operand::Number(vector) => 0xF000 | unsigned(*vector, 8, line)?,
+                _ => return Err(AsmError::new(line, "TRAP expects a trap vector")),
+            }
+        }
+        "RTI" => {
+            expect(0)?;
+            0x8000
+        }
+        "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" => {
+            expect(0)?;
+            let vector = match mnemonic {
+                "GETC" => 0x20,
+                "OUT" => 0x21,
+                "PUTS" => 0x22,
+                "IN" => 0x23,
+                "PUTSP" => 0x24,
+                _ => 0x25,
+            };
+            0xF000 | vector
+        }
+        ".FILL" => {
+            expect(1)?;
+            match &operands[0] {
+                Operand::Number(value) => {
+                    if !(-0x8000..=0xFFFF).contains(value) {
+                        return Err(AsmError::new(
+                            line,
+                            format!("value {} does not fit in 16 bits", value),
+                        ));
+                    }
+                    *value as u16
+                }
+                Operand::Label(label) => resolve(symbols, label, line)?,
+                _ => return Err(AsmError::new(line, ".FILL expects a value or label")),
+            }
+        }
+        ".BLKW" => {
+            if let [Operand::Number(count)] = operands {
+                words.extend(std::iter::repeat_n(0, *count as usize));
+            }
+            return Ok(());
+        }
+        ".STRINGZ" => {
+            if let [Operand::Str(string)] = operands {
+                words.extend(string.chars().map(|ch| ch as u16));
+                words.push(0);
+            }
+            return Ok(());
+        }
+        _ => {
+            expect(1)?;
+            branch_condition(mnemonic, line)? << 9 | offset(0, 9)?
+        }
+    };
+
+    words.push(word);
+    Ok(())
+}
+
+fn branch_condition(mnemonic: &str, line: usize) -> Result<u16, AsmError> {
+    let flags = &mnemonic[2..];
+    if flags.is_empty() {
+        return Ok(0b111);
+    }
+
+    let mut cond = 0;
+    let mut rest = flags;
+    for (flag, bit) in [('N', 0b100), ('Z', 0b010), ('P', 0b001)] {
+        if let Some(stripped) = rest.strip_prefix(flag) {
+            cond |= bit;
+            rest = stripped;
+        }
+    }
+
+    if rest.is_empty() {
+        Ok(cond)
+    } else {
+        Err(AsmError::new(
+            line,
+            format!("unknown instruction '{}'", mnemonic),
+        ))
+    }
+}
+
+fn resolve(symbols: &BTreeMap<String, u16>, label: &str, line: usize) -> Result<u16, AsmError> {
+    symbols
+        .get(label)
+        .copied()
+        .ok_or_else(|| AsmError::new(line, format!("undefined label '{}'", label)))
+}
+
+fn signed(value: i32, bits: u32, line: usize) -> Result<u16, AsmError> {
+    let min = -(1 << (bits - 1));
+    let max = (1 << (bits - 1)) - 1;
+    if value < min || value > max {
+        return Err(AsmError::new(
+            line,
+            format!(
+                "value {} out of range for {}-bit signed field ({}..={})",
+                value, bits, min, max
+            ),
+        ));
+    }
+    Ok((value as u16) & ((1 << bits) - 1) as u16)
+}
+
+fn unsigned(value: i32, bits: u32, line: usize) -> Result<u16, AsmError> {
+    let max = (1i32 << bits) - 1;
+    if value < 0 || value > max {
+        return Err(AsmError::new(
+            line,
+            format!(
+                "value {} out of range for {}-bit unsigned field (0..={})",
+                value, bits, max
+            ),
+        ));
+    }
+    Ok(value as u16)
+}
+
+fn is_mnemonic(word: &str) -> bool {
+    let upper = word.to_ascii_uppercase();
+    if MNEMONICS.contains(&upper.as_str()) {
+        return true;
+    }
+    upper.starts_with("BR") && branch_condition(&upper, 0).is_ok()
+}
+
+fn is_identifier(word: &str) -> bool {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {}
+        _ => return false,
+    }
+    chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+fn parse_operand(token: Token, line: usize) -> Result<Operand, AsmError> {
+    let word = match token {
+        Token::Str(string) => return Ok(Operand::Str(string)),
+        Token::Word(word) => word,
+    };
+
+    let bytes = word.as_bytes();
+    if bytes.len() == 2
+        && (bytes[0] == b'R' || bytes[0] == b'r')
+        && (b'0'..=b'7').contains(&bytes[1])
+    {
+        return Ok(Operand::Register((bytes[1] - b'0') as u16));
+    }
+
+    if let Some(number) = parse_number(&word) {
+        return number
+            .map(Operand::Number)
+            .ok_or_else(|| AsmError::new(line, format!("invalid number '{}'", word)));
+    }
+
+    if is_identifier(&word) {
+        Ok(Operand::Label(word))
+    } else {
+        Err(AsmError::new(line, format!("invalid operand '{}'", word)))
+    }
+}
+
+/// Parses a number literal written as in assembly: `#-5`, `12288`, `-5`,
+/// `x3000`, `x-1` or `0x3000`. Returns `None` if the word is not numeric at
+/// all, and `Some(None)` if it looks numeric but does not parse.
+pub fn parse_number(word: &str) -> Option<Option<i32>> {
+    let (radix, digits) = if let Some(rest) = word.strip_prefix('#') {
+        (10, rest)
+    } else if let Some(rest) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        (16, rest)
+    } else if let Some(rest) = word.strip_prefix(['x', 'X']) {
+        let hex = rest.strip_prefix('-').unwrap_or(rest);
+        if hex.is_empty() || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            return None;
+        }
+        (16, rest)
+    } else if word.starts_with(|ch: char| ch.is_ascii_digit() || ch == '-') {
+        (10, word)
+    } else {
+        return None;
+    };
+
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, digits),
+    };
+    if digits.is_empty() || digits.starts_with(['+', '-']) {
+        return Some(None);
+    }
+
+    let value = i32::from_str_radix(digits, radix).ok();
+    Some(value.map(|value| if negative { -value } else { value }))
+}
+
+/// Parses a number literal that fits in a 16-bit word, as either a signed or
+/// an unsigned value.
+pub fn parse_word(word: &str) -> Option<u16> {
+    match parse_number(word)?? {
+        value @ -0x8000..=0xFFFF => Some(value as u16),
+        _ => None,
+    }
+}
+
+fn tokenize(text: &str, line: usize) -> Result<Vec<Token>, AsmError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut word = String::new();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            ';' => break,
+            '"' => {
+                if !word.is_empty() {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                }
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => string.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some('0') => '\0',
+                            Some('\\') => '\\',
+                            Some('"') => '"',
+                            Some(other) => {
+                                return Err(AsmError::new(
+                                    line,
+                                    format!("unknown escape sequence '\\{}'", other),
+                                ))
+                            }
+                            None => return Err(AsmError::new(line, "unterminated string literal")),
+                        }),
+                        Some(other) if other as u32 > 0xFFFF => {
+                            return Err(AsmError::new(
+                                line,
+                                format!("character '{}' does not fit in 16 bits", other),
+                            ))
+                        }
+                        Some(other) => string.push(other),
+                        None => return Err(AsmError::new(line, "unterminated string literal")),
+                    }
+                }
+                tokens.push(Token::Str(string));
+            }
+            ',' => {
+                if !word.is_empty() {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                }
+            }
+            ch if ch.is_whitespace() => {
+                if !word.is_empty() {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                }
+            }
+            ch => word.push(ch),
+        }
+    }
+
+    if !word.is_empty() {
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(source: &str) -> Vec<u16> {
+        assemble(source).unwrap().image().words().to_vec()
+    }
+
+    fn error(source: &str) -> AsmError {
+        match assemble(source) {
+            Ok(_) => panic!("expected assembly to fail"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn assembles_hello_world() {
+        let program = assemble(
+            "        .ORIG x3000
+                     LEA R0, HELLO   ; load message
+                     PUTS
+                     HALT
+            HELLO    .STRINGZ \"hi\\n\"
+                     .END",
+        )
+        .unwrap();
+
+        assert_eq!(program.image().origin(), 0x3000);
+        assert_eq!(
+            program.image().words(),
+            &[
+                0xE002,
+                0xF022,
+                0xF025,
+                'h' as u16,
+                'i' as u16,
+                '\n' as u16,
+                0
+            ]
+        );
+        assert_eq!(program.symbol("HELLO"), Some(0x3003));
+    }
+
+    #[test]
+    fn encodes_every_opcode() {
+        let source = "
+            .ORIG x3000
+        TOP ADD R1, R2, R3
+            ADD R1, R2, #-16
+            AND R7, R0, #-1
+            AND R7, R0, #15
+            NOT R4, R5
+            BR TOP
+            BRn TOP
+            BRzp TOP
+            BRnzp TOP
+            JMP R2
+            RET
+            JSR TOP
+            JSRR R3
+            LD R1, TOP
+            LDI R2, TOP
+            LDR R3, R4, #-32
+            LEA R5, TOP
+            ST R6, TOP
+            STI R7, TOP
+            STR R0, R1, #31
+            TRAP x26
+            RTI
+            GETC
+            OUT
+            PUTS
+            IN
+            PUTSP
+            HALT
+            .END";
+
+        assert_eq!(
+            words(source),
+            vec![
+                0x1283, 0x12B0, 0x5E3F, 0x5E2F, 0x997F, 0x0FFA, 0x09F9, 0x07F8, 0x0FF7, 0xC080,
+                0xC1C0, 0x4FF4, 0x40C0, 0x23F2, 0xA5F1, 0x6720, 0xEBEF, 0x3DEE, 0xBFED, 0x705F,
+                0xF026, 0x8000, 0xF020, 0xF021, 0xF022, 0xF023, 0xF024, 0xF025,
+            ]
+        );
+    }
+
+    #[test]
+    fn directives_reserve_and_fill() {
+        let program = assemble(
+            ".orig x4000
+            ptr .fill data
+                .fill #-1
+                .fill xBEEF
+                .blkw 3
+            data .stringz \"a\"
+            end:
+                .end",
+        )
+        .unwrap();
+
+        assert_eq!(
+            program.image().words(),
+            &[0x4006, 0xFFFF, 0xBEEF, 0, 0, 0, 'a' as u16, 0]
+        );
+        assert_eq!(program.symbol("end"), Some(0x4008));
+    }
+
+    #[test]
+    fn forward_and_backward_labels() {
+        let words = words(
+            ".ORIG x3000
+            LOOP BRnzp DONE
+                 BRnzp LOOP
+            DONE HALT
+                 .END",
+        );
+        assert_eq!(words, vec![0x0E01, 0x0FFE, 0xF025]);
+    }
+
+    #[test]
+    fn text_after_end_is_ignored() {
+        assert_eq!(words(".ORIG x3000\nHALT\n.END\ngarbage here"), vec![0xF025]);
+    }
+
+    #[test]
+    fn reports_undefined_label_with_line() {
+        let err = error(".ORIG x3000\nLD R0, MISSING\n.END");
+        assert_eq!(err.line(), 2);
+        assert_eq!(err.message(), "undefined label 'MISSING'");
+    }
+
+    #[test]
+    fn reports_out_of_range_offsets() {
+        let err = error(".ORIG x3000\nADD R0, R0, #16\n.END");
+        assert_eq!(err.line(), 2);
+        assert!(err.message().contains("out of range"));
+
+        let err = error(".ORIG x3000\nLDR R0, R1, #32\n.END");
+        assert_eq!(err.line(), 2);
+
+        let err = error(".ORIG x3000\nBR FAR\n.BLKW 256\nFAR HALT\n.END");
+        assert_eq!(err.line(), 2);
+        assert!(err.message().contains("9-bit"));
+
+        let err = error(".ORIG x3000\nTRAP x100\n.END");
+        assert_eq!(err.line(), 2);
+    }
+
+    #[test]
+    fn boundary_offsets_assemble() {
+        let words = words(".ORIG x3000\nBR FAR\n.BLKW 255\nFAR HALT\n.END");
+        assert_eq!(words[0], 0x0EFF);
+    }
+
+    #[test]
+    fn reports_bad_operands() {
+        let err = error(".ORIG x3000\nADD R0, R1\n.END");
+        assert_eq!(err.to_string(), "line 2: ADD expects 3 operand(s), found 2");
+
+        let err = error(".ORIG x3000\nNOT R0, #1\n.END");
+        assert_eq!(err.to_string(), "line 2: NOT operand 2 must be a register");
+
+        let err = error(".ORIG x3000\nADD R8, R0, R0\n.END");
+        assert_eq!(err.line(), 2);
+
+        let err = error(".ORIG x3000\nFOO BAR R0\n.END");
+        assert_eq!(err.to_string(), "line 2: unknown instruction 'BAR'");
+    }
+
+    #[test]
+    fn reports_structural_errors() {
+        assert_eq!(error("HALT\n").to_string(), "line 1: HALT before .ORIG");
+        assert_eq!(
+            error(".ORIG x3000\nHALT").to_string(),
+            "line 2: missing .END"
+        );
+        assert_eq!(
+            error(".ORIG x3000\nA HALT\nA HALT\n.END").to_string(),
+            "line 3: duplicate label 'A'"
+        );
+        assert_eq!(
+            error(".ORIG xFFFF\n.BLKW 2\n.END").to_string(),
+            "line 2: program runs past the end of memory"
+        );
+        assert_eq!(
+            error(".ORIG x3000\n.STRINGZ \"open\n.END").to_string(),
+            "line 2: unterminated string literal"
+        );
+        assert_eq!(
+            error(".ORIG x3000\n.STRINGZ \"ab\\\n.END").to_string(),
+            "line 2: unterminated string literal"
+        );
+        assert_eq!(
+            error(".ORIG x3000\nHALT\n.STRINGZ \"hi \u{1F600}\"\n.END").to_string(),
+            "line 3: character '\u{1F600}' does not fit in 16 bits"
+        );
+    }
+
+    #[test]
+    fn number_literals_parse_as_words() {
+        for (text, value) in [
+            ("x3000", Some(0x3000)),
+            ("0x3000", Some(0x3000)),
+            ("X-1", Some(0xFFFF)),
+            ("#-5", Some(0xFFFB)),
+            ("-5", Some(0xFFFB)),
+            ("12288", Some(0x3000)),
+            ("x1FFFF", None),
+            ("#-32769", None),
+            ("0xZZ", None),
+            ("LOOP", None),
+        ] {
+            assert_eq!(parse_word(text), value, "{}", text);
+        }
+        assert_eq!(parse_number("0xZZ"), Some(None));
+        assert_eq!(parse_number("LOOP"), None);
+    }
+
+    #[test]
+    fn image_round_trips_through_obj_format() {
+        let program = assemble(".ORIG x3000\nHALT\n.END").unwrap();
+        let bytes = program.image().to_bytes();
+        assert_eq!(bytes, vec![0x30, 0x00, 0xF0, 0x25]);
+
+        let image = ObjectImage::from_bytes(&bytes).unwrap();
+        assert_eq!(image.origin(), 0x3000);
+        assert_eq!(image.words(), &[0xF025]);
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Read, Write},
+    rc::Rc,
+    sync::{
+        mpsc::{self, Receiver, TryRecvError},
+        Mutex, MutexGuard, OnceLock, PoisonError,
+    },
+    thread,
+};
+
+/// Byte-oriented terminal used by the trap routines and the memory-mapped
+/// keyboard and display.
+pub trait Console {
+    /// Blocks until a byte of input is available.
+    fn read_byte(&mut self) -> io::Result<u8>;
+    /// Returns a byte of input if one is available without blocking.
+    fn poll_byte(&mut self) -> io::Result<Option<u8>>;
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Console backed by the process stdin/stdout.
+///
+/// Stdin is read on a background thread the first time input is requested so
+/// that `poll_byte` never blocks.
+#[derive(Default)]
+pub struct StdioConsole {
+    output: Option<Box<dyn Write>>,
+}
+
+impl StdioConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads stdin but sends output to `output` instead of stdout.
+    pub fn with_output(output: impl Write + 'static) -> Self {
+        Self {
+            output: Some(Box::new(output)),
+        }
+    }
+}
+
+/// Bytes of stdin, shared by every `StdioConsole` and `StdinReader` so that
+/// they consume one ordered stream.
+fn stdin_channel() -> MutexGuard<'static, Receiver<io::Result<u8>>> {
+    static STDIN: OnceLock<Mutex<Receiver<io::Result<u8>>>> = OnceLock::new();
+    STDIN
+        .get_or_init(|| Mutex::new(spawn_stdin_reader()))
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+fn spawn_stdin_reader() -> Receiver<io::Result<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut input = [0u8; 1];
+        loop {
+            let result = match stdin.read(&mut input) {
+                Ok(0) => break,
+                Ok(_) => Ok(input[0]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = result.is_err();
+            if sender.send(result).is_err() || failed {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn end_of_stdin() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "end of standard input")
+}
+
+/// Reads stdin one byte at a time from the stream `StdioConsole` uses, so a
+/// front end can read its own commands without stealing buffered program
+/// input.
+pub struct StdinReader;
+
+impl Read for StdinReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match stdin_channel().recv() {
+            Ok(result) => {
+                buf[0] = result?;
+                Ok(1)
+            }
+            Err(_) => Ok(0),
+        }
+    }
+}
+
+impl Console for StdioConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        match stdin_channel().recv() {
+            Ok(result) => result,
+            Err(_) => Err(end_of_stdin()),
+        }
+    }
+
+    /// Once stdin is closed no more input can arrive, so polling reports
+    /// `UnexpectedEof` instead of leaving a program spinning on KBSR.
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        match stdin_channel().try_recv() {
+            Ok(result) => result.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(end_of_stdin()),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        match &mut self.output {
+            Some(output) => output.write_all(&[byte]),
+            None => io::stdout().write_all(&[byte]),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            Some(output) => output.flush(),
+            None => io::stdout().flush(),
+        }
+    }
+}
+
+/// Console over arbitrary byte streams, such as files in scripted runs.
+///
+/// Input is treated as always ready: `poll_byte` reads directly from the
+/// stream, and since more input will never arrive, running out of it is an
+/// `UnexpectedEof` error rather than `None`.
+pub struct StreamConsole {
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+}
+
+impl StreamConsole {
+    pub fn new(input: impl Read + 'static, output: impl Write + 'static) -> Self {
+        Self {
+            input: Box::new(input),
+            output: Box::new(output),
+        }
+    }
+}
+
+impl Console for StreamConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        match self.input.read_exact(&mut byte) {
+            Ok(()) => Ok(byte[0]),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "console input exhausted",
+            )),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        self.read_byte().map(Some)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[derive(Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+/// In-memory console with scripted input and captured output.
+///
+/// Clones share the same buffers, so a handle can be kept to inspect the
+/// output after the console has been handed to a `Cpu`.
+#[derive(Clone, Default)]
+pub struct BufferConsole {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+impl BufferConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_input(input: &[u8]) -> Self {
+        let console = Self::new();
+        console.push_input(input);
+        console
+    }
+
+    pub fn push_input(&self, input: &[u8]) {
+        self.buffers.borrow_mut().input.extend(input);
+    }
+
+    pub fn output(&self) -> Vec<u8> {
+        self.buffers.borrow().output.clone()
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.buffers.borrow_mut().output)
+    }
+}
+
+impl Console for BufferConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        self.buffers
+            .borrow_mut()
+            .input
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "console input exhausted"))
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.buffers.borrow_mut().input.pop_front())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.buffers.borrow_mut().output.push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_console_replays_input_then_reports_eof() {
+        let mut console = BufferConsole::with_input(b"ab");
+        assert_eq!(console.read_byte().unwrap(), b'a');
+        assert_eq!(console.read_byte().unwrap(), b'b');
+        let err = console.read_byte().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn buffer_console_poll_does_not_fail_when_empty() {
+        let mut console = BufferConsole::with_input(b"k");
+        assert_eq!(console.poll_byte().unwrap(), Some(b'k'));
+        assert_eq!(console.poll_byte().unwrap(), None);
+    }
+
+    #[test]
+    fn stream_console_input_is_always_ready_until_exhausted() {
+        let mut console = StreamConsole::new(&b"hi"[..], Vec::new());
+        assert_eq!(console.poll_byte().unwrap(), Some(b'h'));
+        assert_eq!(console.read_byte().unwrap(), b'i');
+        let err = console.poll_byte().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn buffer_console_clones_share_output() {
+        let handle = BufferConsole::new();
+        let mut console = handle.clone();
+        console.write_byte(b'x').unwrap();
+        assert_eq!(handle.output(), b"x");
+        assert_eq!(handle.take_output(), b"x");
+        assert!(handle.output().is_empty());
+    }
+}
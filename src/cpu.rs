@@ -1,11 +1,15 @@
-use core::panic;
 use std::{
     char,
-    io::{self, Read, Write},
-    ops::{Index, IndexMut},
+    collections::{HashMap, VecDeque},
+    io,
+    ops::{Index, IndexMut, RangeInclusive},
 };
 
-use crate::memory::Memory;
+use crate::{
+    console::{Console, StdioConsole},
+    error::VmError,
+    memory::{Memory, DEVICE_REGION_START, USER_SPACE_START},
+};
 
 pub enum ConditionFlag {
     POS = 0b001,
@@ -13,19 +17,21 @@ pub enum ConditionFlag {
     NEG = 0b100,
 }
 
-impl Into<u16> for ConditionFlag {
-    fn into(self) -> u16 {
-        return self as u16;
+impl From<ConditionFlag> for u16 {
+    fn from(flag: ConditionFlag) -> Self {
+        flag as u16
     }
 }
 
-impl From<u16> for ConditionFlag {
-    fn from(value: u16) -> Self {
+impl TryFrom<u16> for ConditionFlag {
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
-            0b001 => ConditionFlag::POS,
-            0b010 => ConditionFlag::ZRO,
-            0b100 => ConditionFlag::NEG,
-            _ => panic!("Invalid u16 value: {}", value),
+            0b001 => Ok(ConditionFlag::POS),
+            0b010 => Ok(ConditionFlag::ZRO),
+            0b100 => Ok(ConditionFlag::NEG),
+            _ => Err(value),
         }
     }
 }
@@ -40,10 +46,22 @@ pub enum Register {
     R6,
     R7,
     PC,
-    COND,
+    /// Processor status register: privilege in bit 15, priority in bits
+    /// 10:8 and the N/Z/P condition codes in bits 2:0.
+    PSR,
+    /// Supervisor stack pointer, saved while running in user mode.
+    SSP,
+    /// User stack pointer, saved while running in supervisor mode.
+    USP,
     COUNT,
 }
 
+/// PSR bit that is set while the processor runs in user mode.
+pub const PSR_USER_MODE: u16 = 1 << 15;
+const PSR_PRIORITY_SHIFT: u16 = 8;
+const PSR_PRIORITY_MASK: u16 = 0b111 << PSR_PRIORITY_SHIFT;
+const PSR_CONDITION_MASK: u16 = 0b111;
+
 pub enum Trapcode {
     GETC = 0x20,
     OUT = 0x21,
@@ -53,16 +71,18 @@ pub enum Trapcode {
     HALT = 0x25,
 }
 
-impl From<u16> for Trapcode {
-    fn from(value: u16) -> Self {
+impl TryFrom<u16> for Trapcode {
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
-            0x20 => Trapcode::GETC,
-            0x21 => Trapcode::OUT,
-            0x22 => Trapcode::PUTS,
-            0x23 => Trapcode::IN,
-            0x24 => Trapcode::PUTSP,
-            0x25 => Trapcode::HALT,
-            _ => panic!("Invalid u16 value: {}", value),
+            0x20 => Ok(Trapcode::GETC),
+            0x21 => Ok(Trapcode::OUT),
+            0x22 => Ok(Trapcode::PUTS),
+            0x23 => Ok(Trapcode::IN),
+            0x24 => Ok(Trapcode::PUTSP),
+            0x25 => Ok(Trapcode::HALT),
+            _ => Err(value),
         }
     }
 }
@@ -86,9 +106,9 @@ pub enum Opcode {
     TRAP,
 }
 
-impl Into<u16> for Opcode {
-    fn into(self) -> u16 {
-        return self as u16;
+impl From<Opcode> for u16 {
+    fn from(opcode: Opcode) -> Self {
+        opcode as u16
     }
 }
 
@@ -111,213 +131,858 @@ impl From<u16> for Opcode {
             0b1101 => Opcode::RES,
             0b1110 => Opcode::LEA,
             0b1111 => Opcode::TRAP,
-            _ => panic!("Invalid u16 value: {}", value),
+            _ => unreachable!(),
         }
     }
 }
 
+/// Sign-extends the low `bit_count` bits of `value` to a full 16-bit word.
+pub fn sign_extend(value: u16, bit_count: u32) -> u16 {
+    let shift = 16 - bit_count;
+    (((value << shift) as i16) >> shift) as u16
+}
+
+/// Base of the trap vector table, indexed by trap vector.
+pub const TRAP_VECTOR_TABLE: u16 = 0x0000;
+/// Base of the interrupt vector table, indexed by interrupt or exception
+/// vector.
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    PrivilegeViolation = 0x00,
+    IllegalOpcode = 0x01,
+    AccessViolation = 0x02,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    Normal,
+    Branched {
+        target: u16,
+    },
+    Trapped {
+        vector: u8,
+    },
+    /// An exception was raised and control passed to its handler.
+    Exception {
+        vector: u8,
+    },
+    /// A device interrupt was accepted instead of executing an instruction.
+    Interrupted {
+        vector: u8,
+    },
+    Halted,
+    PoweredOff,
+    /// The instruction completed but touched a watched address.
+    Watchpoint(WatchpointHit),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The native HALT trap routine ran.
+    Halted,
+    /// Bit 15 of the Machine Control Register was cleared.
+    PoweredOff,
+    /// The operating system stopped the clock after a fault; see
+    /// `Memory::faulted`.
+    Faulted,
+    /// The instruction budget given to `execute_limited` ran out.
+    InstructionLimit,
+    /// A watchpoint was hit.
+    Watchpoint(WatchpointHit),
+}
+
+impl ExitReason {
+    /// Why a machine whose clock has stopped is no longer running.
+    pub fn powered_off(memory: &Memory) -> Self {
+        if memory.faulted() {
+            ExitReason::Faulted
+        } else {
+            ExitReason::PoweredOff
+        }
+    }
+}
+
+/// Which data accesses a watchpoint reacts to. Hits report `Read` or
+/// `Write`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, access: WatchKind) -> bool {
+        self == WatchKind::Access || self == access
+    }
+}
+
+/// Watches the data accesses of LD, LDI, LDR, ST, STI and STR to a range of
+/// addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchpointHit {
+    pub pc: u16,
+    pub instr: u16,
+    pub address: u16,
+    pub access: WatchKind,
+    /// The word before the access; equal to `new_value` for reads.
+    pub old_value: u16,
+    pub new_value: u16,
+}
+
+/// What `step_back` undid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UndoneStep {
+    /// The outcome the step reported, or `None` if it failed with a
+    /// `VmError`.
+    pub outcome: Option<StepOutcome>,
+}
+
+/// Everything needed to undo one step: the registers and keyboard state
+/// before it, and the previous contents of every word it stored to.
+struct UndoEntry {
+    registers: [u16; Register::COUNT as usize],
+    keyboard: [u16; 2],
+    writes: Vec<(u16, u16)>,
+    outcome: Option<StepOutcome>,
+}
+
+/// How architectural exceptions (privilege violation, illegal opcode and
+/// access violation) are delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExceptionMode {
+    /// Stop execution and report a `VmError` to the host. Suitable when no
+    /// operating system image with exception handlers is loaded.
+    #[default]
+    Host,
+    /// Vector through the interrupt vector table like the hardware does.
+    Vector,
+}
+
+/// How TRAP instructions reach their service routines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrapMode {
+    /// Run the built-in Rust routines for the standard trap vectors. This is
+    /// the fast path and needs no operating system image in memory.
+    #[default]
+    Native,
+    /// Jump through the trap vector table at x0000-x00FF to routines in
+    /// memory, entering supervisor mode like the hardware does.
+    Memory,
+}
+
+/// A host service invoked by TRAP instead of a built-in or in-memory
+/// routine. R7 already holds the return address when it runs.
+pub trait TrapHandler {
+    fn handle(&mut self, cpu: &mut Cpu, memory: &mut Memory) -> io::Result<()>;
+}
+
+impl<F> TrapHandler for F
+where
+    F: FnMut(&mut Cpu, &mut Memory) -> io::Result<()>,
+{
+    fn handle(&mut self, cpu: &mut Cpu, memory: &mut Memory) -> io::Result<()> {
+        self(cpu, memory)
+    }
+}
+
 pub struct Cpu {
     registers: [u16; Register::COUNT as usize],
+    console: Box<dyn Console>,
+    exception_mode: ExceptionMode,
+    trap_mode: TrapMode,
+    trap_handlers: HashMap<u8, Box<dyn TrapHandler>>,
+    /// The vector whose handler is running, and whether that handler has
+    /// registered or unregistered its own vector since it started.
+    running_trap: Option<(u8, bool)>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchpointHit>,
+    history: VecDeque<UndoEntry>,
+    history_limit: usize,
+    recording: Option<UndoEntry>,
 }
 
 impl Cpu {
     pub fn new() -> Self {
+        Self::with_console(Box::new(StdioConsole::new()))
+    }
+
+    pub fn with_console(console: Box<dyn Console>) -> Self {
         let mut cpu = Self {
             registers: [0; Register::COUNT as usize],
+            console,
+            exception_mode: ExceptionMode::default(),
+            trap_mode: TrapMode::default(),
+            trap_handlers: HashMap::new(),
+            running_trap: None,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            history: VecDeque::new(),
+            history_limit: 0,
+            recording: None,
         };
 
-        cpu[Register::COND] = ConditionFlag::ZRO.into();
+        cpu[Register::PSR] = ConditionFlag::ZRO.into();
         cpu[Register::PC] = 0x3000;
 
-        return cpu;
+        cpu
+    }
+
+    pub fn console_mut(&mut self) -> &mut dyn Console {
+        self.console.as_mut()
+    }
+
+    pub fn exception_mode(&self) -> ExceptionMode {
+        self.exception_mode
+    }
+
+    pub fn set_exception_mode(&mut self, mode: ExceptionMode) {
+        self.exception_mode = mode;
+    }
+
+    pub fn trap_mode(&self) -> TrapMode {
+        self.trap_mode
+    }
+
+    pub fn set_trap_mode(&mut self, mode: TrapMode) {
+        self.trap_mode = mode;
+    }
+
+    /// Installs `handler` for the trap `vector`, returning the handler it
+    /// replaces. Registered handlers take precedence over both the built-in
+    /// routines and the trap vector table.
+    pub fn register_trap(
+        &mut self,
+        vector: u8,
+        handler: impl TrapHandler + 'static,
+    ) -> Option<Box<dyn TrapHandler>> {
+        self.mark_trap_changed(vector);
+        self.trap_handlers.insert(vector, Box::new(handler))
+    }
+
+    /// Removes the handler for the trap `vector`. A handler may unregister
+    /// itself while it runs; the call then returns `None`, since the handler
+    /// is still in use, and it is dropped once it returns.
+    pub fn unregister_trap(&mut self, vector: u8) -> Option<Box<dyn TrapHandler>> {
+        self.mark_trap_changed(vector);
+        self.trap_handlers.remove(&vector)
+    }
+
+    fn mark_trap_changed(&mut self, vector: u8) {
+        if let Some((running, changed)) = &mut self.running_trap {
+            if *running == vector {
+                *changed = true;
+            }
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { range, kind });
+    }
+
+    /// Removes every watchpoint on exactly `range`, returning whether there
+    /// were any.
+    pub fn remove_watchpoints(&mut self, range: &RangeInclusive<u16>) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.range != *range);
+        self.watchpoints.len() != len
+    }
+
+    /// Removes the watchpoints of exactly `kind` on `range`, returning
+    /// whether there were any.
+    pub fn remove_watchpoint(&mut self, range: &RangeInclusive<u16>, kind: WatchKind) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.range != *range || watchpoint.kind != kind);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Keeps undo entries for the last `limit` steps so they can be reversed
+    /// with `step_back`; zero, the default, turns recording off and drops the
+    /// history. Console I/O and memory written by registered trap handlers
+    /// are not undone.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn history_limit(&self) -> usize {
+        self.history_limit
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Restores the registers and memory to what they were before the most
+    /// recently recorded step. Returns `None` once the history is exhausted.
+    pub fn step_back(&mut self, memory: &mut Memory) -> Option<UndoneStep> {
+        let entry = self.history.pop_back()?;
+        for &(address, value) in entry.writes.iter().rev() {
+            memory[address] = value;
+        }
+        memory.restore_keyboard(entry.keyboard);
+        // steps only run with the clock on, even if a trap handler stopped it
+        memory.set_clock_enabled(true);
+        self.registers = entry.registers;
+        Some(UndoneStep {
+            outcome: entry.outcome,
+        })
     }
 
     fn fetch(&mut self, memory: &Memory) -> u16 {
         let value = memory[self[Register::PC]];
-        self[Register::PC] += 1;
-        return value;
+        self[Register::PC] = self[Register::PC].wrapping_add(1);
+        value
     }
 
     fn update_flags(&mut self, r: u16) {
-        if self[r] == 0 {
-            self[Register::COND] = ConditionFlag::ZRO.into();
+        let flag = if self[r] == 0 {
+            ConditionFlag::ZRO
         } else if self[r] >> 15 == 1 {
-            self[Register::COND] = ConditionFlag::NEG.into();
+            ConditionFlag::NEG
         } else {
-            self[Register::COND] = ConditionFlag::POS.into();
+            ConditionFlag::POS
+        };
+        self[Register::PSR] = (self[Register::PSR] & !PSR_CONDITION_MASK) | u16::from(flag);
+    }
+
+    /// The N/Z/P bits of the PSR.
+    pub fn condition(&self) -> u16 {
+        self[Register::PSR] & PSR_CONDITION_MASK
+    }
+
+    pub fn user_mode(&self) -> bool {
+        self[Register::PSR] & PSR_USER_MODE != 0
+    }
+
+    /// Switches privilege mode, swapping R6 between the user and supervisor
+    /// stacks when the mode actually changes.
+    pub fn set_user_mode(&mut self, user: bool) {
+        if user == self.user_mode() {
+            return;
+        }
+
+        if user {
+            self[Register::SSP] = self[Register::R6];
+            self[Register::R6] = self[Register::USP];
+            self[Register::PSR] |= PSR_USER_MODE;
+        } else {
+            self[Register::USP] = self[Register::R6];
+            self[Register::R6] = self[Register::SSP];
+            self[Register::PSR] &= !PSR_USER_MODE;
         }
     }
 
-    pub fn execute(&mut self, memory: &mut Memory) {
+    pub fn priority(&self) -> u8 {
+        ((self[Register::PSR] & PSR_PRIORITY_MASK) >> PSR_PRIORITY_SHIFT) as u8
+    }
+
+    pub fn set_priority(&mut self, priority: u8) {
+        let bits = ((priority as u16) << PSR_PRIORITY_SHIFT) & PSR_PRIORITY_MASK;
+        self[Register::PSR] = (self[Register::PSR] & !PSR_PRIORITY_MASK) | bits;
+    }
+
+    pub fn execute(&mut self, memory: &mut Memory) -> Result<ExitReason, VmError> {
         loop {
-            let instr = self.fetch(memory);
-            match Opcode::from(instr) {
-                Opcode::BR => {
-                    let cond: u16 = (instr >> 9) & 0b111;
-                    let pc_offset = instr & 0b11111111;
-                    if cond & self[Register::COND] == 1 {
-                        self[Register::PC] += pc_offset;
-                    }
+            match self.step(memory)? {
+                StepOutcome::Halted => return Ok(ExitReason::Halted),
+                StepOutcome::PoweredOff => return Ok(ExitReason::powered_off(memory)),
+                StepOutcome::Watchpoint(hit) => return Ok(ExitReason::Watchpoint(hit)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Like `execute`, but gives up after `max_instructions` steps.
+    pub fn execute_limited(
+        &mut self,
+        memory: &mut Memory,
+        max_instructions: u64,
+    ) -> Result<ExitReason, VmError> {
+        for _ in 0..max_instructions {
+            match self.step(memory)? {
+                StepOutcome::Halted => return Ok(ExitReason::Halted),
+                StepOutcome::PoweredOff => return Ok(ExitReason::powered_off(memory)),
+                StepOutcome::Watchpoint(hit) => return Ok(ExitReason::Watchpoint(hit)),
+                _ => {}
+            }
+        }
+        Ok(ExitReason::InstructionLimit)
+    }
+
+    /// Runs the interrupt entry sequence: switches to supervisor mode at the
+    /// given priority, pushes PSR and PC onto the supervisor stack and jumps
+    /// through the interrupt vector table.
+    pub fn interrupt(&mut self, memory: &mut Memory, vector: u8, priority: u8) -> io::Result<()> {
+        let entry = INTERRUPT_VECTOR_TABLE + vector as u16;
+        self.enter_service_routine(memory, entry, Some(priority))
+    }
+
+    fn service_interrupts(&mut self, memory: &mut Memory) -> Result<Option<u8>, VmError> {
+        let pc = self[Register::PC];
+        let instr = memory[pc];
+        let io_error = |source| VmError::Io { pc, instr, source };
+
+        let request = memory
+            .pending_interrupt(self.console.as_mut())
+            .map_err(io_error)?;
+        match request {
+            Some((vector, priority)) if priority > self.priority() => {
+                self.interrupt(memory, vector, priority).map_err(io_error)?;
+                Ok(Some(vector))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Delivers an exception raised by the instruction `instr` at `pc`
+    /// according to the configured `ExceptionMode`.
+    fn raise_exception(
+        &mut self,
+        memory: &mut Memory,
+        exception: Exception,
+        pc: u16,
+        instr: u16,
+    ) -> Result<StepOutcome, VmError> {
+        match self.exception_mode {
+            ExceptionMode::Host => Err(match exception {
+                Exception::PrivilegeViolation => VmError::PrivilegeViolation { pc, instr },
+                Exception::IllegalOpcode => VmError::IllegalOpcode { pc, instr },
+                Exception::AccessViolation => VmError::AccessViolation { pc, instr },
+            }),
+            ExceptionMode::Vector => {
+                let vector = exception as u8;
+                let entry = INTERRUPT_VECTOR_TABLE + vector as u16;
+                self.enter_service_routine(memory, entry, None)
+                    .map_err(|source| VmError::Io { pc, instr, source })?;
+                Ok(StepOutcome::Exception { vector })
+            }
+        }
+    }
+
+    /// Switches to supervisor mode, pushes PSR and PC onto the supervisor
+    /// stack and jumps to the address stored in the vector table `entry`.
+    fn enter_service_routine(
+        &mut self,
+        memory: &mut Memory,
+        entry: u16,
+        priority: Option<u8>,
+    ) -> io::Result<()> {
+        let psr = self[Register::PSR];
+        self.set_user_mode(false);
+        if let Some(priority) = priority {
+            self.set_priority(priority);
+        }
+
+        let sp = self[Register::R6].wrapping_sub(1);
+        self.store(memory, sp, psr)?;
+        let sp = sp.wrapping_sub(1);
+        self.store(memory, sp, self[Register::PC])?;
+        self[Register::R6] = sp;
+
+        self[Register::PC] = self.load(memory, entry)?;
+        Ok(())
+    }
+
+    /// User-mode accesses to system space or the device registers fault.
+    fn access_violation(&self, address: u16) -> bool {
+        self.user_mode() && !(USER_SPACE_START..DEVICE_REGION_START).contains(&address)
+    }
+
+    fn load(&mut self, memory: &mut Memory, address: u16) -> io::Result<u16> {
+        memory.read(address, self.console.as_mut())
+    }
+
+    fn store(&mut self, memory: &mut Memory, address: u16, value: u16) -> io::Result<()> {
+        if let Some(entry) = &mut self.recording {
+            entry.writes.push((address, memory[address]));
+        }
+        memory.write(address, value, self.console.as_mut())
+    }
+
+    /// `load` for the data accesses of LD, LDI and LDR, checked against the
+    /// watchpoints.
+    fn load_data(&mut self, memory: &mut Memory, address: u16) -> io::Result<u16> {
+        let value = self.load(memory, address)?;
+        self.watch(address, WatchKind::Read, value, value);
+        Ok(value)
+    }
+
+    /// `store` for the data accesses of ST, STI and STR, checked against the
+    /// watchpoints.
+    fn store_data(&mut self, memory: &mut Memory, address: u16, value: u16) -> io::Result<()> {
+        let old_value = memory[address];
+        self.store(memory, address, value)?;
+        self.watch(address, WatchKind::Write, old_value, value);
+        Ok(())
+    }
+
+    /// Records the first watched access of the current instruction; `step`
+    /// fills in its PC and instruction.
+    fn watch(&mut self, address: u16, access: WatchKind, old_value: u16, new_value: u16) {
+        let watched = self.watchpoints.iter().any(|watchpoint| {
+            watchpoint.kind.matches(access) && watchpoint.range.contains(&address)
+        });
+        if watched && self.watch_hit.is_none() {
+            self.watch_hit = Some(WatchpointHit {
+                pc: 0,
+                instr: 0,
+                address,
+                access,
+                old_value,
+                new_value,
+            });
+        }
+    }
+
+    fn write_char(&mut self, ch: char) -> io::Result<()> {
+        let mut buf = [0u8; 4];
+        self.write_str(ch.encode_utf8(&mut buf))
+    }
+
+    fn write_str(&mut self, s: &str) -> io::Result<()> {
+        for byte in s.bytes() {
+            self.console.write_byte(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Executes exactly one instruction, unless the clock has been stopped
+    /// through the Machine Control Register or a pending device interrupt
+    /// outranks the current priority level.
+    pub fn step(&mut self, memory: &mut Memory) -> Result<StepOutcome, VmError> {
+        if !memory.clock_enabled() {
+            return Ok(StepOutcome::PoweredOff);
+        }
+        if self.history_limit == 0 {
+            return self.step_clocked(memory);
+        }
+
+        self.recording = Some(UndoEntry {
+            registers: self.registers,
+            keyboard: memory.keyboard(),
+            writes: Vec::new(),
+            outcome: None,
+        });
+        let result = self.step_clocked(memory);
+        if let Some(mut entry) = self.recording.take() {
+            entry.outcome = result.as_ref().ok().copied();
+            if self.history.len() == self.history_limit {
+                self.history.pop_front();
+            }
+            self.history.push_back(entry);
+        }
+        result
+    }
+
+    fn step_clocked(&mut self, memory: &mut Memory) -> Result<StepOutcome, VmError> {
+        if let Some(vector) = self.service_interrupts(memory)? {
+            return Ok(StepOutcome::Interrupted { vector });
+        }
+
+        let pc = self[Register::PC];
+        let instr = memory[pc];
+        self.watch_hit = None;
+        let outcome = self.execute_instruction(memory)?;
+        if let Some(hit) = self.watch_hit.take() {
+            return Ok(StepOutcome::Watchpoint(WatchpointHit { pc, instr, ..hit }));
+        }
+        if outcome != StepOutcome::Halted && !memory.clock_enabled() {
+            return Ok(StepOutcome::PoweredOff);
+        }
+        Ok(outcome)
+    }
+
+    fn execute_instruction(&mut self, memory: &mut Memory) -> Result<StepOutcome, VmError> {
+        let pc = self[Register::PC];
+        if self.access_violation(pc) {
+            // the fetch state that checks for ACV also increments PC, so the
+            // saved PC is PC+1 as for every other exception
+            self[Register::PC] = pc.wrapping_add(1);
+            return self.raise_exception(memory, Exception::AccessViolation, pc, memory[pc]);
+        }
+
+        let instr = self.fetch(memory);
+        let io_error = |source| VmError::Io { pc, instr, source };
+        match Opcode::from(instr) {
+            Opcode::BR => {
+                let cond = (instr >> 9) & 0b111;
+                let pc_offset = sign_extend(instr & 0x1FF, 9);
+                if cond & self.condition() != 0 {
+                    let target = self[Register::PC].wrapping_add(pc_offset);
+                    self[Register::PC] = target;
+                    return Ok(StepOutcome::Branched { target });
+                }
+            }
+            Opcode::ADD => {
+                let dr = (instr >> 9) & 0b111;
+                let sr1 = (instr >> 6) & 0b111;
+                if (instr >> 5) & 0b1 == 0 {
+                    let sr2 = instr & 0b111;
+                    self[dr] = self[sr1].wrapping_add(self[sr2]);
+                } else {
+                    let imm = sign_extend(instr & 0x1F, 5);
+                    self[dr] = self[sr1].wrapping_add(imm);
                 }
-                Opcode::ADD => {
-                    let dr = (instr >> 9) & 0b111;
-                    let sr1 = (instr >> 6) & 0b111;
-                    if (instr >> 5) & 0b1 == 0 {
-                        let sr2 = instr & 0b111;
-                        self[dr] = self[sr1] + self[sr2];
-                    } else {
-                        let imm = instr & 0b11111;
-                        self[dr] = self[sr1] + imm;
-                    }
 
-                    self.update_flags(dr);
+                self.update_flags(dr);
+            }
+            Opcode::LD => {
+                let dr = (instr >> 9) & 0b111;
+                let pc_offset = sign_extend(instr & 0x1FF, 9);
+                let address = self[Register::PC].wrapping_add(pc_offset);
+                if self.access_violation(address) {
+                    return self.raise_exception(memory, Exception::AccessViolation, pc, instr);
                 }
-                Opcode::LD => {
-                    let dr = (&instr >> 9) & 0b111;
-                    let pc_offset = instr & 0b11111111;
-                    self[Register::R0] = memory[self[Register::PC] + pc_offset];
-                    self.update_flags(dr);
+                self[dr] = self.load_data(memory, address).map_err(io_error)?;
+                self.update_flags(dr);
+            }
+            Opcode::ST => {
+                let sr = (instr >> 9) & 0b111;
+                let pc_offset = sign_extend(instr & 0x1FF, 9);
+                let address = self[Register::PC].wrapping_add(pc_offset);
+                if self.access_violation(address) {
+                    return self.raise_exception(memory, Exception::AccessViolation, pc, instr);
                 }
-                Opcode::ST => {
-                    let dr = (instr >> 9) & 0b111;
-                    let pc_offset = instr & 0b11111111;
-                    memory[self[Register::PC] + pc_offset] = self[dr];
+                self.store_data(memory, address, self[sr])
+                    .map_err(io_error)?;
+            }
+            Opcode::JSR => {
+                let return_address = self[Register::PC];
+                if (instr >> 11) & 0b1 == 1 {
+                    let pc_offset = sign_extend(instr & 0x7FF, 11);
+                    self[Register::PC] = return_address.wrapping_add(pc_offset);
+                } else {
+                    let base_r = (instr >> 6) & 0b111;
+                    self[Register::PC] = self[base_r];
                 }
-                Opcode::JSR => {
-                    self[Register::R7] = self[Register::PC];
-                    if (instr >> 11) & 0b1 == 1 {
-                        let pc_offset = instr & 0b1111111111;
-                        self[Register::PC] += pc_offset;
-                    } else {
-                        let r1 = (instr >> 6) & 0b111;
-                        self[Register::PC] = self[r1];
-                    }
+                self[Register::R7] = return_address;
+                return Ok(StepOutcome::Branched {
+                    target: self[Register::PC],
+                });
+            }
+            Opcode::AND => {
+                let dr = (instr >> 9) & 0b111;
+                let sr1 = (instr >> 6) & 0b111;
+                if (instr >> 5) & 0b1 == 0 {
+                    let sr2 = instr & 0b111;
+                    self[dr] = self[sr1] & self[sr2]
+                } else {
+                    let imm = sign_extend(instr & 0x1F, 5);
+                    self[dr] = self[sr1] & imm;
                 }
-                Opcode::AND => {
-                    let dr = (instr >> 9) & 0b111;
-                    let sr1 = (instr >> 6) & 0b111;
-                    if (instr >> 5) & 0b1 == 0 {
-                        let sr2 = instr & 0b111;
-                        self[dr] = self[sr1] & self[sr2]
-                    } else {
-                        let imm = instr & 0b11111;
-                        self[dr] = self[sr1] & imm;
-                    }
 
-                    self.update_flags(dr);
+                self.update_flags(dr);
+            }
+            Opcode::LDR => {
+                let dr = (instr >> 9) & 0b111;
+                let base_r = (instr >> 6) & 0b111;
+                let offset = sign_extend(instr & 0x3F, 6);
+                let address = self[base_r].wrapping_add(offset);
+                if self.access_violation(address) {
+                    return self.raise_exception(memory, Exception::AccessViolation, pc, instr);
                 }
-                Opcode::LDR => {
-                    let dr = (instr >> 9) & 0b111;
-                    let base_r = (instr >> 6) & 0b111;
-                    let offset = instr & 0b111111;
-                    self[dr] = memory[self[base_r] + offset];
-                    self.update_flags(dr);
+                self[dr] = self.load_data(memory, address).map_err(io_error)?;
+                self.update_flags(dr);
+            }
+            Opcode::STR => {
+                let sr = (instr >> 9) & 0b111;
+                let base_r = (instr >> 6) & 0b111;
+                let offset = sign_extend(instr & 0x3F, 6);
+                let address = self[base_r].wrapping_add(offset);
+                if self.access_violation(address) {
+                    return self.raise_exception(memory, Exception::AccessViolation, pc, instr);
                 }
-                Opcode::STR => {
-                    let sr = (instr >> 9) & 0b111;
-                    let base_r = (instr >> 6) & 0b111;
-                    let offset = (instr >> 6) & 0b11111;
-                    memory[self[base_r] + offset] = self[sr];
+                self.store_data(memory, address, self[sr])
+                    .map_err(io_error)?;
+            }
+            Opcode::RTI => {
+                if self.user_mode() {
+                    return self.raise_exception(memory, Exception::PrivilegeViolation, pc, instr);
+                }
+
+                let sp = self[Register::R6];
+                let target = self.load(memory, sp).map_err(io_error)?;
+                let psr = self.load(memory, sp.wrapping_add(1)).map_err(io_error)?;
+                self[Register::R6] = sp.wrapping_add(2);
+                self[Register::PC] = target;
+                self[Register::PSR] = psr & !PSR_USER_MODE;
+                self.set_user_mode(psr & PSR_USER_MODE != 0);
+                return Ok(StepOutcome::Branched { target });
+            }
+            Opcode::NOT => {
+                let dr = (instr >> 9) & 0b111;
+                let sr = (instr >> 6) & 0b111;
+                self[dr] = !self[sr];
+                self.update_flags(dr);
+            }
+            Opcode::LDI => {
+                let dr = (instr >> 9) & 0b111;
+                let pc_offset = sign_extend(instr & 0x1FF, 9);
+                let loc = self[Register::PC].wrapping_add(pc_offset);
+                if self.access_violation(loc) {
+                    return self.raise_exception(memory, Exception::AccessViolation, pc, instr);
                 }
-                Opcode::RTI => panic!("Bad opcode: {:#b}", instr),
-                Opcode::NOT => {
-                    let dr = (instr >> 9) & 0b111;
-                    let sr = (instr >> 6) & 0b111;
-                    self[dr] = !self[sr];
-                    self.update_flags(dr);
+                let address = self.load_data(memory, loc).map_err(io_error)?;
+                if self.access_violation(address) {
+                    return self.raise_exception(memory, Exception::AccessViolation, pc, instr);
                 }
-                Opcode::LDI => {
-                    let dr = (instr >> 9) & 0b111;
-                    let pc_offset = instr & 0b11111111;
-                    let loc = self[Register::PC] + pc_offset;
-                    self[dr] = memory[memory[loc]];
-                    self.update_flags(dr);
+                self[dr] = self.load_data(memory, address).map_err(io_error)?;
+                self.update_flags(dr);
+            }
+            Opcode::STI => {
+                let sr = (instr >> 9) & 0b111;
+                let pc_offset = sign_extend(instr & 0x1FF, 9);
+                let loc = self[Register::PC].wrapping_add(pc_offset);
+                if self.access_violation(loc) {
+                    return self.raise_exception(memory, Exception::AccessViolation, pc, instr);
                 }
-                Opcode::STI => {
-                    let dr = (instr >> 9) & 0b111;
-                    let pc_offset = instr & 0b11111111;
-                    let addr = memory[self[Register::PC] + pc_offset];
-                    memory[addr] = self[dr];
+                let address = self.load_data(memory, loc).map_err(io_error)?;
+                if self.access_violation(address) {
+                    return self.raise_exception(memory, Exception::AccessViolation, pc, instr);
                 }
-                Opcode::JMP => {
-                    let r1 = (instr >> 6) & 0b111;
-                    self[Register::PC] = self[r1];
+                self.store_data(memory, address, self[sr])
+                    .map_err(io_error)?;
+            }
+            Opcode::JMP => {
+                let base_r = (instr >> 6) & 0b111;
+                let target = self[base_r];
+                self[Register::PC] = target;
+                return Ok(StepOutcome::Branched { target });
+            }
+            Opcode::RES => {
+                return self.raise_exception(memory, Exception::IllegalOpcode, pc, instr);
+            }
+            Opcode::LEA => {
+                let dr = (instr >> 9) & 0b111;
+                let pc_offset = sign_extend(instr & 0x1FF, 9);
+                self[dr] = self[Register::PC].wrapping_add(pc_offset);
+                self.update_flags(dr);
+            }
+            Opcode::TRAP => {
+                self[Register::R7] = self[Register::PC];
+                let vector = instr & 0xFF;
+                if let Some(mut handler) = self.trap_handlers.remove(&(vector as u8)) {
+                    let outer = self.running_trap.replace((vector as u8, false));
+                    let result = handler.handle(self, memory);
+                    // a handler that replaced or unregistered itself keeps
+                    // that change
+                    if let Some((_, false)) = self.running_trap {
+                        self.trap_handlers.insert(vector as u8, handler);
+                    }
+                    self.running_trap = outer;
+                    result.map_err(io_error)?;
+                    return Ok(StepOutcome::Trapped {
+                        vector: vector as u8,
+                    });
                 }
-                Opcode::RES => panic!("Bad opcode: {:#b}", instr),
-                Opcode::LEA => {
-                    let dr = (instr >> 9) & 0b111;
-                    let pc_offset = instr & 0b11111111;
-                    self[dr] = self[Register::PC] + pc_offset;
-                    self.update_flags(dr);
+
+                if self.trap_mode == TrapMode::Memory {
+                    self.enter_service_routine(memory, TRAP_VECTOR_TABLE + vector, None)
+                        .map_err(io_error)?;
+                    return Ok(StepOutcome::Trapped {
+                        vector: vector as u8,
+                    });
                 }
-                Opcode::TRAP => {
-                    self[Register::R7] = self[Register::PC];
-                    let trap = Trapcode::from(instr & 0b11111111);
-                    match trap {
-                        Trapcode::GETC => {
-                            let mut input = [0u8; 1];
-                            io::stdin().read(&mut input).unwrap();
-                            self[Register::R0] = input[0] as u16;
-                        }
-                        Trapcode::OUT => {
-                            let ch = char::from_u32(self[Register::R0] as u32).unwrap();
-                            print!("{}", ch);
-                        }
-                        Trapcode::PUTS => {
-                            let mut loc = self[Register::R0];
-                            let mut chars = String::new();
-                            loop {
-                                let chr = memory[loc];
-                                if chr == 0 {
-                                    break;
-                                }
-
-                                let ch = char::from_u32(chr.into()).unwrap();
-                                chars.push(ch);
-                                loc += 1;
+
+                let trap = Trapcode::try_from(vector).map_err(|_| VmError::UnknownTrap {
+                    pc,
+                    instr,
+                    vector: vector as u8,
+                })?;
+                let to_char = |value: u16| {
+                    char::from_u32(value.into()).ok_or(VmError::InvalidCharacter {
+                        pc,
+                        instr,
+                        value,
+                    })
+                };
+
+                match trap {
+                    Trapcode::GETC => {
+                        self.console.flush().map_err(io_error)?;
+                        let input = self.console.read_byte().map_err(io_error)?;
+                        self[Register::R0] = input as u16;
+                    }
+                    Trapcode::OUT => {
+                        let ch = to_char(self[Register::R0])?;
+                        self.write_char(ch).map_err(io_error)?;
+                    }
+                    Trapcode::PUTS => {
+                        let mut loc = self[Register::R0];
+                        loop {
+                            let chr = memory[loc];
+                            if chr == 0 {
+                                break;
                             }
 
-                            print!("{}", chars);
-                        }
-                        Trapcode::IN => {
-                            print!("Enter a character: ");
-                            io::stdout().flush().unwrap();
-
-                            let mut input = [0u8; 1];
-                            io::stdin().read(&mut input).unwrap();
-                            self[Register::R0] = input[0] as u16;
-                            self.update_flags(0); // Register::R0
+                            self.write_char(to_char(chr)?).map_err(io_error)?;
+                            loc = loc.wrapping_add(1);
                         }
-                        Trapcode::PUTSP => {
-                            let mut loc = self[Register::R0];
-                            let mut chars = String::new();
-                            loop {
-                                let chr = memory[loc];
-                                if chr == 0 {
-                                    break;
-                                }
-
-                                chars.push(char::from_u32((chr & 0xFF).into()).unwrap());
-
-                                let chr2 = chr >> 8;
-                                if chr2 != 0 {
-                                    let ch2 = char::from_u32(chr2.into()).unwrap();
-                                    chars.push(ch2);
-                                }
-
-                                loc += 1;
+                    }
+                    Trapcode::IN => {
+                        self.write_str("Enter a character: ").map_err(io_error)?;
+                        self.console.flush().map_err(io_error)?;
+
+                        let input = self.console.read_byte().map_err(io_error)?;
+                        self[Register::R0] = input as u16;
+                        self.update_flags(0); // Register::R0
+                    }
+                    Trapcode::PUTSP => {
+                        let mut loc = self[Register::R0];
+                        loop {
+                            let chr = memory[loc];
+                            if chr == 0 {
+                                break;
                             }
 
-                            print!("{}", chars);
-                        }
-                        Trapcode::HALT => {
-                            println!("HALT");
-                            return;
+                            let chr1 = (chr & 0xFF) as u8;
+                            self.write_char(chr1.into()).map_err(io_error)?;
+
+                            let chr2 = (chr >> 8) as u8;
+                            if chr2 != 0 {
+                                self.write_char(chr2.into()).map_err(io_error)?;
+                            }
+
+                            loc = loc.wrapping_add(1);
                         }
                     }
+                    Trapcode::HALT => {
+                        self.write_str("HALT\n").map_err(io_error)?;
+                        self.console.flush().map_err(io_error)?;
+                        return Ok(StepOutcome::Halted);
+                    }
                 }
+
+                return Ok(StepOutcome::Trapped {
+                    vector: vector as u8,
+                });
             }
         }
+
+        Ok(StepOutcome::Normal)
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -325,7 +990,7 @@ impl Index<Register> for Cpu {
     type Output = u16;
 
     fn index(&self, index: Register) -> &Self::Output {
-        return &self.registers[index as usize];
+        &self.registers[index as usize]
     }
 }
 
@@ -348,3 +1013,1293 @@ impl IndexMut<u16> for Cpu {
         &mut self.registers[index as usize]
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, console::BufferConsole, memory::KBSR};
+
+    const HALT: u16 = 0xF025;
+
+    fn run_at(origin: u16, program: &[u16], memory: &mut Memory) -> Cpu {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        memory.write_at(program, origin as usize);
+        cpu[Register::PC] = origin;
+        assert_eq!(cpu.execute(memory).unwrap(), ExitReason::Halted);
+        cpu
+    }
+
+    fn run_with_console(program: &[u16], console: &BufferConsole) -> Cpu {
+        let mut cpu = Cpu::with_console(Box::new(console.clone()));
+        let mut memory = Memory::new();
+        memory.write_at(program, 0x3000);
+        assert_eq!(cpu.execute(&mut memory).unwrap(), ExitReason::Halted);
+        cpu
+    }
+
+    fn run_err(program: &[u16]) -> VmError {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        let mut memory = Memory::new();
+        memory.write_at(program, 0x3000);
+        cpu.execute(&mut memory).unwrap_err()
+    }
+
+    fn run(program: &[u16]) -> (Cpu, Memory) {
+        let mut memory = Memory::new();
+        let cpu = run_at(0x3000, program, &mut memory);
+        (cpu, memory)
+    }
+
+    fn cond(cpu: &Cpu) -> u16 {
+        cpu.condition()
+    }
+
+    #[test]
+    fn sign_extend_keeps_positive_values() {
+        assert_eq!(sign_extend(0b01111, 5), 15);
+        assert_eq!(sign_extend(0x0FF, 9), 255);
+        assert_eq!(sign_extend(0x3FF, 11), 1023);
+    }
+
+    #[test]
+    fn sign_extend_fills_negative_values() {
+        assert_eq!(sign_extend(0b11111, 5), 0xFFFF);
+        assert_eq!(sign_extend(0b10000, 5), (-16i16) as u16);
+        assert_eq!(sign_extend(0x100, 9), (-256i16) as u16);
+        assert_eq!(sign_extend(0x400, 11), (-1024i16) as u16);
+        assert_eq!(sign_extend(0x20, 6), (-32i16) as u16);
+    }
+
+    #[test]
+    fn new_cpu_starts_at_user_space_with_zero_flag() {
+        let cpu = Cpu::new();
+        assert_eq!(cpu[Register::PC], 0x3000);
+        assert_eq!(cond(&cpu), ConditionFlag::ZRO as u16);
+    }
+
+    #[test]
+    fn new_cpu_starts_in_supervisor_mode_at_priority_zero() {
+        let cpu = Cpu::new();
+        assert_eq!(cpu[Register::PSR], 0x0002);
+        assert!(!cpu.user_mode());
+        assert_eq!(cpu.priority(), 0);
+    }
+
+    #[test]
+    fn switching_privilege_swaps_stacks() {
+        let mut cpu = Cpu::new();
+        cpu[Register::R6] = 0x2FF0;
+
+        cpu.set_user_mode(true);
+        assert!(cpu.user_mode());
+        assert_eq!(cpu[Register::PSR], PSR_USER_MODE | 0x0002);
+        assert_eq!(cpu[Register::SSP], 0x2FF0);
+        assert_eq!(cpu[Register::R6], 0);
+
+        cpu[Register::R6] = 0xFDFF;
+        cpu.set_user_mode(true);
+        assert_eq!(cpu[Register::R6], 0xFDFF);
+
+        cpu.set_user_mode(false);
+        assert!(!cpu.user_mode());
+        assert_eq!(cpu[Register::R6], 0x2FF0);
+        assert_eq!(cpu[Register::USP], 0xFDFF);
+    }
+
+    #[test]
+    fn priority_and_flags_share_the_psr() {
+        let mut cpu = Cpu::new();
+        cpu.set_priority(4);
+        cpu.set_user_mode(true);
+        assert_eq!(cpu[Register::PSR], 0x8402);
+        assert_eq!(cpu.priority(), 4);
+
+        let mut memory = Memory::new();
+        // ADD R1, R1, #-1 keeps the privilege and priority bits
+        memory[0x3000] = 0b0001_001_001_1_11111;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu[Register::PSR], 0x8404);
+    }
+
+    #[test]
+    fn add_register_mode() {
+        // AND R1, R1, #0; ADD R1, R1, #7; AND R2, R2, #0; ADD R2, R2, #5; ADD R3, R1, R2
+        let (cpu, _) = run(&[
+            0b0101_001_001_1_00000,
+            0b0001_001_001_1_00111,
+            0b0101_010_010_1_00000,
+            0b0001_010_010_1_00101,
+            0b0001_011_001_000_010,
+            HALT,
+        ]);
+        assert_eq!(cpu[Register::R3], 12);
+        assert_eq!(cond(&cpu), ConditionFlag::POS as u16);
+    }
+
+    #[test]
+    fn add_immediate_is_sign_extended() {
+        // ADD R1, R1, #-1
+        let (cpu, _) = run(&[0b0001_001_001_1_11111, HALT]);
+        assert_eq!(cpu[Register::R1], 0xFFFF);
+        assert_eq!(cond(&cpu), ConditionFlag::NEG as u16);
+    }
+
+    #[test]
+    fn add_immediate_extremes() {
+        // ADD R1, R1, #15; ADD R2, R2, #-16
+        let (cpu, _) = run(&[0b0001_001_001_1_01111, 0b0001_010_010_1_10000, HALT]);
+        assert_eq!(cpu[Register::R1], 15);
+        assert_eq!(cpu[Register::R2], (-16i16) as u16);
+    }
+
+    #[test]
+    fn add_wraps_on_overflow() {
+        // LD R1, #2; ADD R1, R1, #1; HALT; .FILL x7FFF
+        let (cpu, _) = run(&[0b0010_001_000000010, 0b0001_001_001_1_00001, HALT, 0x7FFF]);
+        assert_eq!(cpu[Register::R1], 0x8000);
+        assert_eq!(cond(&cpu), ConditionFlag::NEG as u16);
+
+        // LD R1, #2; ADD R1, R1, #1; HALT; .FILL xFFFF
+        let (cpu, _) = run(&[0b0010_001_000000010, 0b0001_001_001_1_00001, HALT, 0xFFFF]);
+        assert_eq!(cpu[Register::R1], 0);
+        assert_eq!(cond(&cpu), ConditionFlag::ZRO as u16);
+    }
+
+    #[test]
+    fn and_register_and_immediate_modes() {
+        // LD R1, #3; LD R2, #3; AND R3, R1, R2; AND R4, R1, #-1; HALT; .FILL xF0F0; .FILL x0FF0
+        let (cpu, _) = run(&[
+            0b0010_001_000000100,
+            0b0010_010_000000100,
+            0b0101_011_001_000_010,
+            0b0101_100_001_1_11111,
+            HALT,
+            0xF0F0,
+            0x0FF0,
+        ]);
+        assert_eq!(cpu[Register::R3], 0x00F0);
+        assert_eq!(cpu[Register::R4], 0xF0F0);
+        assert_eq!(cond(&cpu), ConditionFlag::NEG as u16);
+    }
+
+    #[test]
+    fn and_with_zero_sets_zero_flag() {
+        // ADD R1, R1, #5; AND R1, R1, #0
+        let (cpu, _) = run(&[0b0001_001_001_1_00101, 0b0101_001_001_1_00000, HALT]);
+        assert_eq!(cpu[Register::R1], 0);
+        assert_eq!(cond(&cpu), ConditionFlag::ZRO as u16);
+    }
+
+    #[test]
+    fn not_inverts_and_sets_flags() {
+        // NOT R2, R1
+        let (cpu, _) = run(&[0b1001_010_001_111111, HALT]);
+        assert_eq!(cpu[Register::R2], 0xFFFF);
+        assert_eq!(cond(&cpu), ConditionFlag::NEG as u16);
+
+        // ADD R1, R1, #-1; NOT R2, R1
+        let (cpu, _) = run(&[0b0001_001_001_1_11111, 0b1001_010_001_111111, HALT]);
+        assert_eq!(cpu[Register::R2], 0);
+        assert_eq!(cond(&cpu), ConditionFlag::ZRO as u16);
+    }
+
+    #[test]
+    fn br_taken_for_each_condition() {
+        // ADD R1, R1, #<value>; BR<cond> #1; ADD R2, R2, #1; HALT
+        for (value, cond_bits) in [(0b00001, 0b001), (0b00000, 0b010), (0b11111, 0b100)] {
+            let (cpu, _) = run(&[
+                0b0001_001_001_1_00000 | value,
+                (cond_bits << 9) | 1,
+                0b0001_010_010_1_00001,
+                HALT,
+            ]);
+            assert_eq!(cpu[Register::R2], 0, "BR with cond {:03b}", cond_bits);
+        }
+    }
+
+    #[test]
+    fn br_not_taken_when_condition_mismatches() {
+        // ADD R1, R1, #1; BRnz #1; ADD R2, R2, #1; HALT
+        let (cpu, _) = run(&[
+            0b0001_001_001_1_00001,
+            0b0000_110_000000001,
+            0b0001_010_010_1_00001,
+            HALT,
+        ]);
+        assert_eq!(cpu[Register::R2], 1);
+
+        // NOP (BR with no condition bits) never branches
+        let (cpu, _) = run(&[0b0000_000_000000001, 0b0001_010_010_1_00001, HALT]);
+        assert_eq!(cpu[Register::R2], 1);
+    }
+
+    #[test]
+    fn br_negative_offset_loops_backwards() {
+        // ADD R1, R1, #3; loop: ADD R2, R2, #2; ADD R1, R1, #-1; BRp loop; HALT
+        let (cpu, _) = run(&[
+            0b0001_001_001_1_00011,
+            0b0001_010_010_1_00010,
+            0b0001_001_001_1_11111,
+            0b0000_001_111111101,
+            HALT,
+        ]);
+        assert_eq!(cpu[Register::R2], 6);
+        assert_eq!(cpu[Register::R1], 0);
+    }
+
+    #[test]
+    fn br_boundary_offsets() {
+        let mut memory = Memory::new();
+        // BRnzp #255 lands on a HALT 256 words after the branch
+        memory[0x3000 + 256] = HALT;
+        let cpu = run_at(0x3000, &[0b0000_111_011111111], &mut memory);
+        assert_eq!(cpu[Register::PC], 0x3000 + 257);
+
+        let mut memory = Memory::new();
+        // BRnzp #-256 lands 255 words before the branch
+        memory[0x3100 - 255] = HALT;
+        let cpu = run_at(0x3100, &[0b0000_111_100000000], &mut memory);
+        assert_eq!(cpu[Register::PC], 0x3100 - 254);
+    }
+
+    #[test]
+    fn ld_writes_destination_register() {
+        // LD R3, #1; HALT; .FILL x1234
+        let (cpu, _) = run(&[0b0010_011_000000001, HALT, 0x1234]);
+        assert_eq!(cpu[Register::R3], 0x1234);
+        assert_eq!(cpu[Register::R0], 0);
+        assert_eq!(cond(&cpu), ConditionFlag::POS as u16);
+    }
+
+    #[test]
+    fn ld_negative_offset() {
+        let mut memory = Memory::new();
+        memory[0x2FFF] = 0x8000;
+        // LD R1, #-2
+        let cpu = run_at(0x3000, &[0b0010_001_111111110, HALT], &mut memory);
+        assert_eq!(cpu[Register::R1], 0x8000);
+        assert_eq!(cond(&cpu), ConditionFlag::NEG as u16);
+    }
+
+    #[test]
+    fn st_stores_source_register() {
+        // ADD R4, R4, #9; ST R4, #1; HALT; .BLKW 1
+        let (_, memory) = run(&[0b0001_100_100_1_01001, 0b0011_100_000000001, HALT, 0]);
+        assert_eq!(memory[0x3003], 9);
+    }
+
+    #[test]
+    fn ldi_loads_through_pointer() {
+        // LDI R2, #1; HALT; .FILL x4000
+        let mut memory = Memory::new();
+        memory[0x4000] = 0xBEEF;
+        let cpu = run_at(0x3000, &[0b1010_010_000000001, HALT, 0x4000], &mut memory);
+        assert_eq!(cpu[Register::R2], 0xBEEF);
+        assert_eq!(cond(&cpu), ConditionFlag::NEG as u16);
+    }
+
+    #[test]
+    fn sti_stores_through_pointer() {
+        // ADD R2, R2, #-3; STI R2, #1; HALT; .FILL x4000
+        let (_, memory) = run(&[0b0001_010_010_1_11101, 0b1011_010_000000001, HALT, 0x4000]);
+        assert_eq!(memory[0x4000], (-3i16) as u16);
+    }
+
+    #[test]
+    fn ldr_uses_signed_offset6() {
+        let mut memory = Memory::new();
+        memory[0x301F + 0x1F] = 7;
+        memory[0x301F - 0x20] = 0;
+        // LEA R1, #30; LDR R2, R1, #31; LDR R3, R1, #-32
+        let cpu = run_at(
+            0x3000,
+            &[
+                0b1110_001_000011110,
+                0b0110_010_001_011111,
+                0b0110_011_001_100000,
+                HALT,
+            ],
+            &mut memory,
+        );
+        assert_eq!(cpu[Register::R1], 0x301F);
+        assert_eq!(cpu[Register::R2], 7);
+        assert_eq!(cpu[Register::R3], 0);
+        assert_eq!(cond(&cpu), ConditionFlag::ZRO as u16);
+    }
+
+    #[test]
+    fn str_uses_offset6_field() {
+        // LEA R1, #9; ADD R2, R2, #4; STR R2, R1, #-2; STR R2, R1, #3; HALT
+        let (_, memory) = run(&[
+            0b1110_001_000001001,
+            0b0001_010_010_1_00100,
+            0b0111_010_001_111110,
+            0b0111_010_001_000011,
+            HALT,
+        ]);
+        assert_eq!(memory[0x300A - 2], 4);
+        assert_eq!(memory[0x300A + 3], 4);
+    }
+
+    #[test]
+    fn store_instructions_do_not_touch_flags() {
+        // ADD R1, R1, #-1; AND R2, R2, #0 (Z); ST R1, #1; HALT; .BLKW 1
+        let (cpu, _) = run(&[
+            0b0001_001_001_1_11111,
+            0b0101_010_010_1_00000,
+            0b0011_001_000000001,
+            HALT,
+            0,
+        ]);
+        assert_eq!(cond(&cpu), ConditionFlag::ZRO as u16);
+    }
+
+    #[test]
+    fn jmp_jumps_to_base_register() {
+        // LEA R3, #2; JMP R3; ADD R1, R1, #1; HALT
+        let (cpu, _) = run(&[
+            0b1110_011_000000010,
+            0b1100_000_011_000000,
+            0b0001_001_001_1_00001,
+            HALT,
+        ]);
+        assert_eq!(cpu[Register::R1], 0);
+    }
+
+    #[test]
+    fn jsr_and_ret() {
+        // JSR sub; ADD R2, R2, #1; HALT; sub: ADD R1, R1, #5; RET
+        let (cpu, _) = run(&[
+            0b0100_1_00000000010,
+            0b0001_010_010_1_00001,
+            HALT,
+            0b0001_001_001_1_00101,
+            0b1100_000_111_000000,
+        ]);
+        assert_eq!(cpu[Register::R1], 5);
+        assert_eq!(cpu[Register::R2], 1);
+        assert_eq!(cpu[Register::R7], 0x3003);
+    }
+
+    #[test]
+    fn jsr_negative_offset() {
+        let mut memory = Memory::new();
+        memory[0x2F00] = HALT;
+        // JSR #-257
+        let cpu = run_at(0x3000, &[0b0100_1_11011111111], &mut memory);
+        assert_eq!(cpu[Register::PC], 0x2F01);
+        assert_eq!(cpu[Register::R7], 0x2F01);
+    }
+
+    #[test]
+    fn jsrr_jumps_to_base_register() {
+        // LEA R4, #3; JSRR R4; HALT; HALT; ADD R1, R1, #1; RET
+        let (cpu, _) = run(&[
+            0b1110_100_000000011,
+            0b0100_0_00_100_000000,
+            HALT,
+            HALT,
+            0b0001_001_001_1_00001,
+            0b1100_000_111_000000,
+        ]);
+        assert_eq!(cpu[Register::R1], 1);
+        assert_eq!(cpu[Register::PC], 0x3003);
+    }
+
+    #[test]
+    fn jsrr_through_r7_uses_old_value() {
+        // LEA R7, #2; JSRR R7; HALT; ADD R1, R1, #1; HALT
+        let (cpu, _) = run(&[
+            0b1110_111_000000010,
+            0b0100_0_00_111_000000,
+            HALT,
+            0b0001_001_001_1_00001,
+            HALT,
+        ]);
+        assert_eq!(cpu[Register::R1], 1);
+    }
+
+    #[test]
+    fn lea_computes_address() {
+        // LEA R5, #-1
+        let (cpu, _) = run(&[0b1110_101_111111111, HALT]);
+        assert_eq!(cpu[Register::R5], 0x3000);
+        assert_eq!(cond(&cpu), ConditionFlag::POS as u16);
+    }
+
+    #[test]
+    fn pc_wraps_around_end_of_memory() {
+        let mut memory = Memory::new();
+        memory[0x0000] = HALT;
+        // ADD R1, R1, #1 at xFFFF, then fetch wraps to x0000
+        let cpu = run_at(0xFFFF, &[0b0001_001_001_1_00001], &mut memory);
+        assert_eq!(cpu[Register::R1], 1);
+        assert_eq!(cpu[Register::PC], 0x0001);
+    }
+
+    #[test]
+    fn pc_relative_addressing_wraps() {
+        let mut memory = Memory::new();
+        memory[0xFFFF] = 0x0042;
+        // LD R1, #-2 at x0000 reads xFFFF
+        let cpu = run_at(0x0000, &[0b0010_001_111111110, HALT], &mut memory);
+        assert_eq!(cpu[Register::R1], 0x0042);
+    }
+
+    #[test]
+    fn trap_puts_and_putsp_write_to_console() {
+        let console = BufferConsole::new();
+        // LEA R0, #4; TRAP x22; LEA R0, #5; TRAP x24; HALT; "ok\0"; "hey\0" packed
+        let cpu = run_with_console(
+            &[
+                0b1110_000_000000100,
+                0xF022,
+                0b1110_000_000000101,
+                0xF024,
+                HALT,
+                'o' as u16,
+                'k' as u16,
+                0,
+                u16::from_le_bytes(*b"he"),
+                'y' as u16,
+                0,
+            ],
+            &console,
+        );
+        assert_eq!(cpu[Register::R7], 0x3005);
+        assert_eq!(console.output(), b"okheyHALT\n");
+    }
+
+    #[test]
+    fn trap_out_encodes_non_ascii_as_utf8() {
+        let console = BufferConsole::new();
+        // LD R0, #2; TRAP x21; HALT; .FILL xE9
+        run_with_console(&[0b0010_000_000000010, 0xF021, HALT, 0xE9], &console);
+        assert_eq!(console.output(), "\u{e9}HALT\n".as_bytes());
+    }
+
+    #[test]
+    fn trap_halt_stops_execution() {
+        // HALT; ADD R1, R1, #1
+        let (cpu, _) = run(&[HALT, 0b0001_001_001_1_00001]);
+        assert_eq!(cpu[Register::R1], 0);
+        assert_eq!(cpu[Register::PC], 0x3001);
+        assert_eq!(cpu[Register::R7], 0x3001);
+    }
+
+    #[test]
+    fn trap_getc_reads_character() {
+        let console = BufferConsole::with_input(b"A");
+        let cpu = run_with_console(&[0xF020, HALT], &console);
+        assert_eq!(cpu[Register::R0], 'A' as u16);
+        assert_eq!(console.output(), b"HALT\n");
+    }
+
+    #[test]
+    fn trap_in_prompts_and_sets_flags() {
+        let console = BufferConsole::with_input(b"z");
+        let cpu = run_with_console(&[0xF023, HALT], &console);
+        assert_eq!(cpu[Register::R0], 'z' as u16);
+        assert_eq!(cond(&cpu), ConditionFlag::POS as u16);
+        assert_eq!(console.output(), b"Enter a character: HALT\n");
+    }
+
+    #[test]
+    fn trap_getc_reports_exhausted_input() {
+        let err = run_err(&[0xF020]);
+        assert!(matches!(err, VmError::Io { pc: 0x3000, .. }));
+    }
+
+    #[test]
+    fn polls_keyboard_and_echoes_through_display() {
+        let console = BufferConsole::with_input(b"Q");
+        // POLL LDI R1, KBSR; BRzp POLL; LDI R0, KBDR; STI R0, DDR; HALT
+        let cpu = run_with_console(
+            &[
+                0b1010_001_000000100,
+                0b0000_011_111111110,
+                0b1010_000_000000011,
+                0b1011_000_000000011,
+                HALT,
+                0xFE00,
+                0xFE02,
+                0xFE06,
+            ],
+            &console,
+        );
+        assert_eq!(cpu[Register::R0], 'Q' as u16);
+        assert_eq!(console.output(), b"QHALT\n");
+    }
+
+    #[test]
+    fn clearing_mcr_powers_off() {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        let mut memory = Memory::new();
+        // AND R0, R0, #0; STI R0, MCR; ADD R1, R1, #1; .FILL xFFFE
+        memory.write_at(
+            &[
+                0b0101_000_000_1_00000,
+                0b1011_000_000000001,
+                0b0001_001_001_1_00001,
+                0xFFFE,
+            ],
+            0x3000,
+        );
+
+        assert_eq!(cpu.execute(&mut memory).unwrap(), ExitReason::PoweredOff);
+        assert_eq!(cpu[Register::PC], 0x3002);
+        assert_eq!(cpu[Register::R1], 0);
+
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::PoweredOff);
+        assert_eq!(cpu[Register::PC], 0x3002);
+    }
+
+    #[test]
+    fn mcr_writes_that_keep_clock_running_continue() {
+        // LD R0, #3; STI R0, #3; HALT; .BLKW 1; .FILL x8001; .FILL xFFFE
+        let (_, memory) = run(&[
+            0b0010_000_000000011,
+            0b1011_000_000000011,
+            HALT,
+            0,
+            0x8001,
+            0xFFFE,
+        ]);
+        assert_eq!(memory[0xFFFE], 0x8001);
+    }
+
+    #[test]
+    fn step_reports_each_outcome() {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        let mut memory = Memory::new();
+        // ADD R1, R1, #1; BRp #1; .BLKW 1; LEA R0, #2; TRAP x22; JMP R0; HALT
+        memory.write_at(
+            &[
+                0b0001_001_001_1_00001,
+                0b0000_001_000000001,
+                0,
+                0b1110_000_000000010,
+                0xF022,
+                0b1100_000_000_000000,
+                HALT,
+            ],
+            0x3000,
+        );
+
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::Normal);
+        assert_eq!(cpu[Register::PC], 0x3001);
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Branched { target: 0x3003 }
+        );
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::Normal);
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Trapped { vector: 0x22 }
+        );
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Branched { target: 0x3006 }
+        );
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu[Register::PC], 0x3007);
+    }
+
+    #[test]
+    fn step_does_not_report_untaken_branch() {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        let mut memory = Memory::new();
+        // BRp #5 with Z set
+        memory[0x3000] = 0b0000_001_000000101;
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::Normal);
+        assert_eq!(cpu[Register::PC], 0x3001);
+    }
+
+    #[test]
+    fn unknown_trap_vector_is_an_error() {
+        // ADD R1, R1, #1; TRAP x26
+        let err = run_err(&[0b0001_001_001_1_00001, 0xF026]);
+        assert!(matches!(err, VmError::UnknownTrap { vector: 0x26, .. }));
+        assert_eq!(err.pc(), 0x3001);
+        assert_eq!(err.instr(), 0xF026);
+    }
+
+    #[test]
+    fn interrupt_pushes_state_and_vectors_through_ivt() {
+        let mut cpu = Cpu::new();
+        let mut memory = Memory::new();
+        memory[INTERRUPT_VECTOR_TABLE + 0x80] = 0x1000;
+        cpu[Register::PC] = 0x3005;
+        cpu[Register::R6] = 0xFD00;
+        cpu[Register::SSP] = 0x2FF0;
+        cpu[Register::PSR] = PSR_USER_MODE | 0x0001;
+
+        cpu.interrupt(&mut memory, 0x80, 4).unwrap();
+
+        assert_eq!(cpu[Register::PC], 0x1000);
+        assert!(!cpu.user_mode());
+        assert_eq!(cpu.priority(), 4);
+        assert_eq!(cpu[Register::R6], 0x2FEE);
+        assert_eq!(cpu[Register::USP], 0xFD00);
+        assert_eq!(memory[0x2FEF], 0x8001);
+        assert_eq!(memory[0x2FEE], 0x3005);
+    }
+
+    #[test]
+    fn rti_restores_pc_psr_and_user_stack() {
+        let mut cpu = Cpu::new();
+        let mut memory = Memory::new();
+        // Interrupted user program at x3005, handler at x1000 is a lone RTI
+        memory[INTERRUPT_VECTOR_TABLE + 0x80] = 0x1000;
+        memory[0x1000] = 0x8000;
+        cpu[Register::PC] = 0x3005;
+        cpu[Register::R6] = 0xFD00;
+        cpu[Register::SSP] = 0x3000;
+        cpu[Register::PSR] = PSR_USER_MODE | 0x0004;
+        cpu.interrupt(&mut memory, 0x80, 4).unwrap();
+
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Branched { target: 0x3005 }
+        );
+        assert_eq!(cpu[Register::PC], 0x3005);
+        assert_eq!(cpu[Register::PSR], PSR_USER_MODE | 0x0004);
+        assert_eq!(cpu[Register::R6], 0xFD00);
+        assert_eq!(cpu[Register::SSP], 0x3000);
+    }
+
+    #[test]
+    fn rti_between_supervisor_contexts_keeps_stack() {
+        let mut cpu = Cpu::new();
+        let mut memory = Memory::new();
+        memory[0x3000] = 0x8000;
+        memory[0x2FFE] = 0x0400;
+        memory[0x2FFF] = 0x0301;
+        cpu[Register::R6] = 0x2FFE;
+
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu[Register::PC], 0x0400);
+        assert_eq!(cpu[Register::PSR], 0x0301);
+        assert_eq!(cpu[Register::R6], 0x3000);
+    }
+
+    #[test]
+    fn keyboard_interrupt_preempts_user_program() {
+        let console = BufferConsole::new();
+        let mut cpu = Cpu::with_console(Box::new(console.clone()));
+        let program = assemble(
+            ".ORIG x1000
+            ; keyboard service routine: echo the key and return
+            ISR  LDI R0, KBDR
+                 STI R0, DDR
+                 RTI
+            KBDR .FILL xFE02
+            DDR  .FILL xFE06
+                 .END",
+        )
+        .unwrap();
+        let mut memory = Memory::new();
+        program.image().load_into(&mut memory);
+        memory[INTERRUPT_VECTOR_TABLE + 0x80] = 0x1000;
+        // ADD R1, R1, #1; BRnzp #-2
+        memory.write_at(&[0b0001_001_001_1_00001, 0b0000_111_111111110], 0x3000);
+        memory[KBSR] = 1 << 14;
+        cpu[Register::R6] = 0x3000;
+        cpu.set_user_mode(true);
+
+        for _ in 0..4 {
+            assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::Normal);
+            cpu.step(&mut memory).unwrap();
+        }
+
+        console.push_input(b"x");
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Interrupted { vector: 0x80 }
+        );
+        assert_eq!(cpu[Register::PC], 0x1000);
+        assert_eq!(cpu.priority(), 4);
+        assert!(!cpu.user_mode());
+
+        for _ in 0..3 {
+            cpu.step(&mut memory).unwrap();
+        }
+        assert_eq!(console.output(), b"x");
+        assert_eq!(cpu[Register::PC], 0x3000);
+        assert!(cpu.user_mode());
+        assert_eq!(cpu.priority(), 0);
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::Normal);
+    }
+
+    #[test]
+    fn keyboard_interrupt_waits_for_lower_priority() {
+        let console = BufferConsole::with_input(b"x");
+        let mut cpu = Cpu::with_console(Box::new(console));
+        let mut memory = Memory::new();
+        memory[KBSR] = 1 << 14;
+        // ADD R1, R1, #1
+        memory[0x3000] = 0b0001_001_001_1_00001;
+        cpu.set_priority(4);
+
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::Normal);
+        assert_eq!(cpu[Register::R1], 1);
+
+        cpu.set_priority(3);
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Interrupted { vector: 0x80 }
+        );
+    }
+
+    #[test]
+    fn rti_in_user_mode_is_a_privilege_violation() {
+        let mut cpu = Cpu::new();
+        let mut memory = Memory::new();
+        memory[0x3000] = 0x8000;
+        memory[INTERRUPT_VECTOR_TABLE] = 0x0A00;
+        cpu[Register::R6] = 0x3000;
+        cpu.set_user_mode(true);
+        cpu.set_exception_mode(ExceptionMode::Vector);
+
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Exception { vector: 0x00 }
+        );
+        assert_eq!(cpu[Register::PC], 0x0A00);
+        assert!(!cpu.user_mode());
+        assert_eq!(memory[0x2FFE], 0x3001);
+        assert_eq!(memory[0x2FFF], PSR_USER_MODE | 0x0002);
+    }
+
+    #[test]
+    fn rti_in_user_mode_reports_host_error_by_default() {
+        let mut cpu = Cpu::new();
+        let mut memory = Memory::new();
+        memory[0x3000] = 0x8000;
+        cpu.set_user_mode(true);
+
+        let err = cpu.step(&mut memory).unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::PrivilegeViolation {
+                pc: 0x3000,
+                instr: 0x8000
+            }
+        ));
+        assert!(cpu.user_mode());
+    }
+
+    #[test]
+    fn reserved_opcode_vectors_to_illegal_opcode_handler() {
+        let mut cpu = Cpu::new();
+        let mut memory = Memory::new();
+        memory[0x3000] = 0xD123;
+        memory[INTERRUPT_VECTOR_TABLE + 0x01] = 0x0B00;
+        cpu[Register::R6] = 0x2000;
+        cpu.set_exception_mode(ExceptionMode::Vector);
+
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Exception { vector: 0x01 }
+        );
+        assert_eq!(cpu[Register::PC], 0x0B00);
+        assert_eq!(cpu[Register::R6], 0x1FFE);
+        assert_eq!(memory[0x1FFE], 0x3001);
+        assert_eq!(memory[0x1FFF], 0x0002);
+    }
+
+    fn user_cpu(console: &BufferConsole) -> Cpu {
+        let mut cpu = Cpu::with_console(Box::new(console.clone()));
+        cpu[Register::R6] = 0x3000;
+        cpu.set_user_mode(true);
+        cpu.set_exception_mode(ExceptionMode::Vector);
+        cpu
+    }
+
+    #[test]
+    fn user_mode_accesses_to_system_space_raise_acv() {
+        // LD, ST, LDR, STR, LDI, STI with a pointer into system space
+        let programs: [&[u16]; 6] = [
+            &[0b0010_000_100000000],
+            &[0b0011_000_100000000],
+            &[0b0110_000_001_000000],
+            &[0b0111_000_001_000000],
+            &[0b1010_000_000000001, 0, 0x0200],
+            &[0b1011_000_000000001, 0, 0xFE06],
+        ];
+
+        for program in programs {
+            let console = BufferConsole::new();
+            let mut cpu = user_cpu(&console);
+            let mut memory = Memory::new();
+            memory.write_at(program, 0x3000);
+            memory[INTERRUPT_VECTOR_TABLE + 0x02] = 0x0C00;
+            cpu[Register::R1] = 0x2FFF;
+
+            assert_eq!(
+                cpu.step(&mut memory).unwrap(),
+                StepOutcome::Exception { vector: 0x02 },
+                "{:#06x}",
+                program[0]
+            );
+            assert_eq!(cpu[Register::PC], 0x0C00);
+            assert!(!cpu.user_mode());
+            assert!(console.output().is_empty());
+        }
+    }
+
+    #[test]
+    fn user_mode_ldi_faults_on_pointer_location() {
+        let console = BufferConsole::new();
+        let mut cpu = user_cpu(&console);
+        let mut memory = Memory::new();
+        // LDI R0, #-256 reads its pointer from x2F01
+        memory[0x3000] = 0b1010_000_100000000;
+        memory[INTERRUPT_VECTOR_TABLE + 0x02] = 0x0C00;
+
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Exception { vector: 0x02 }
+        );
+    }
+
+    #[test]
+    fn user_mode_fetch_from_system_space_raises_acv() {
+        let console = BufferConsole::new();
+        let mut cpu = user_cpu(&console);
+        let mut memory = Memory::new();
+        memory[INTERRUPT_VECTOR_TABLE + 0x02] = 0x0C00;
+        cpu[Register::PC] = 0x0400;
+
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Exception { vector: 0x02 }
+        );
+        assert_eq!(memory[0x2FFE], 0x0401);
+    }
+
+    #[test]
+    fn user_mode_accesses_to_user_space_are_allowed() {
+        let console = BufferConsole::new();
+        let mut cpu = user_cpu(&console);
+        let mut memory = Memory::new();
+        // LDR R0, R1, #0; STR R0, R1, #1
+        memory.write_at(&[0b0110_000_001_000000, 0b0111_000_001_000001], 0x3000);
+        memory[0xFDFE] = 9;
+        cpu[Register::R1] = 0xFDFE;
+
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::Normal);
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::Normal);
+        assert_eq!(memory[0xFDFF], 9);
+    }
+
+    #[test]
+    fn access_violation_reports_host_error_by_default() {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        let mut memory = Memory::new();
+        // LDI R0, #1 through a pointer to KBSR
+        memory.write_at(&[0b1010_000_000000001, 0, 0xFE00], 0x3000);
+        cpu.set_user_mode(true);
+
+        let err = cpu.step(&mut memory).unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::AccessViolation {
+                pc: 0x3000,
+                instr: 0xA001
+            }
+        ));
+    }
+
+    #[test]
+    fn supervisor_mode_may_access_devices() {
+        let console = BufferConsole::new();
+        // LDI R0, #1 through a pointer to DSR
+        let cpu = run_with_console(&[0b1010_000_000000001, HALT, 0xFE04], &console);
+        assert_eq!(cpu[Register::R0], 0x8000);
+    }
+
+    #[test]
+    fn memory_trap_mode_jumps_through_trap_vector_table() {
+        let console = BufferConsole::new();
+        let mut cpu = user_cpu(&console);
+        cpu.set_trap_mode(TrapMode::Memory);
+        let os = assemble(
+            ".ORIG x0400
+            ; print the character in R0 through the display and return
+            OUTR STI R0, DDR
+                 RTI
+            DDR  .FILL xFE06
+                 .END",
+        )
+        .unwrap();
+        let mut memory = Memory::new();
+        os.image().load_into(&mut memory);
+        memory[0x0021] = 0x0400;
+        memory[0x0030] = 0x0400;
+        // LD R0, #3; OUT; TRAP x30; ADD R1, R1, #1; .FILL 'A'
+        memory.write_at(
+            &[
+                0b0010_000_000000011,
+                0xF021,
+                0xF030,
+                0b0001_001_001_1_00001,
+                'A' as u16,
+            ],
+            0x3000,
+        );
+
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Trapped { vector: 0x21 }
+        );
+        assert_eq!(cpu[Register::PC], 0x0400);
+        assert_eq!(cpu[Register::R7], 0x3002);
+        assert!(!cpu.user_mode());
+        assert_eq!(cpu[Register::R6], 0x2FFE);
+        assert_eq!(memory[0x2FFE], 0x3002);
+        assert_eq!(memory[0x2FFF], PSR_USER_MODE | 0x0001);
+
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu[Register::PC], 0x3002);
+        assert!(cpu.user_mode());
+
+        assert_eq!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Trapped { vector: 0x30 }
+        );
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu[Register::R1], 1);
+        assert_eq!(console.output(), b"AA");
+    }
+
+    #[test]
+    fn registered_trap_handlers_run_host_services() {
+        let console = BufferConsole::new();
+        let mut cpu = Cpu::with_console(Box::new(console.clone()));
+        // x26 prints R0 as a decimal number, x27 sums the words at R0..R0+R1
+        cpu.register_trap(0x26, |cpu: &mut Cpu, _: &mut Memory| {
+            let text = format!("{}", cpu[Register::R0] as i16);
+            text.bytes()
+                .try_for_each(|byte| cpu.console_mut().write_byte(byte))
+        });
+        cpu.register_trap(0x27, |cpu: &mut Cpu, memory: &mut Memory| {
+            let start = cpu[Register::R0];
+            let sum = (0..cpu[Register::R1]).fold(0u16, |sum, i| {
+                sum.wrapping_add(memory[start.wrapping_add(i)])
+            });
+            cpu[Register::R0] = sum;
+            Ok(())
+        });
+
+        let mut memory = Memory::new();
+        // LEA R0, #4; AND R1, R1, #0; ADD R1, R1, #2; TRAP x27; TRAP x26; HALT
+        memory.write_at(
+            &[
+                0b1110_000_000000101,
+                0b0101_001_001_1_00000,
+                0b0001_001_001_1_00010,
+                0xF027,
+                0xF026,
+                HALT,
+                40,
+                (-42i16) as u16,
+            ],
+            0x3000,
+        );
+        assert_eq!(cpu.execute(&mut memory).unwrap(), ExitReason::Halted);
+        assert_eq!(cpu[Register::R0], (-2i16) as u16);
+        assert_eq!(console.output(), b"-2HALT\n");
+    }
+
+    #[test]
+    fn registered_trap_handlers_override_builtin_and_memory_routines() {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        cpu.set_trap_mode(TrapMode::Memory);
+        cpu.register_trap(0x25, |_: &mut Cpu, memory: &mut Memory| {
+            memory[crate::memory::MCR] = 0;
+            Ok(())
+        });
+        let mut memory = Memory::new();
+        memory[0x3000] = HALT;
+
+        assert_eq!(cpu.step(&mut memory).unwrap(), StepOutcome::PoweredOff);
+        assert_eq!(cpu[Register::PC], 0x3001);
+
+        assert!(cpu.unregister_trap(0x25).is_some());
+        assert!(cpu.unregister_trap(0x25).is_none());
+    }
+
+    #[test]
+    fn trap_handlers_can_replace_or_unregister_themselves() {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        // x26 runs once; x27 adds 1 the first time and 10 afterwards
+        cpu.register_trap(0x26, |cpu: &mut Cpu, _: &mut Memory| {
+            cpu[Register::R1] += 1;
+            assert!(cpu.unregister_trap(0x26).is_none());
+            Ok(())
+        });
+        cpu.register_trap(0x27, |cpu: &mut Cpu, _: &mut Memory| {
+            cpu[Register::R2] += 1;
+            cpu.register_trap(0x27, |cpu: &mut Cpu, _: &mut Memory| {
+                cpu[Register::R2] += 10;
+                Ok(())
+            });
+            Ok(())
+        });
+        let mut memory = Memory::new();
+        memory.write_at(&[0xF027, 0xF027, 0xF026, 0xF026], 0x3000);
+
+        for _ in 0..3 {
+            cpu.step(&mut memory).unwrap();
+        }
+        assert_eq!(cpu[Register::R1], 1);
+        assert_eq!(cpu[Register::R2], 11);
+        assert!(cpu.unregister_trap(0x26).is_none());
+        assert!(matches!(
+            cpu.step(&mut memory),
+            Err(VmError::UnknownTrap { .. })
+        ));
+    }
+
+    #[test]
+    fn trap_handler_errors_are_reported_with_the_trap_address() {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        cpu.register_trap(0x26, |_: &mut Cpu, _: &mut Memory| {
+            Err(io::Error::other("service unavailable"))
+        });
+        let mut memory = Memory::new();
+        memory[0x3000] = 0xF026;
+
+        let err = cpu.execute(&mut memory).unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::Io {
+                pc: 0x3000,
+                instr: 0xF026,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn execute_limited_stops_after_instruction_budget() {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        let mut memory = Memory::new();
+        // BRnzp #-1
+        memory[0x3000] = 0b0000_111_111111111;
+        assert_eq!(
+            cpu.execute_limited(&mut memory, 100).unwrap(),
+            ExitReason::InstructionLimit
+        );
+
+        memory[0x3000] = HALT;
+        assert_eq!(
+            cpu.execute_limited(&mut memory, 1).unwrap(),
+            ExitReason::Halted
+        );
+    }
+
+    #[test]
+    fn watchpoints_report_data_accesses() {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        let mut memory = Memory::new();
+        // ADD R0, R0, #5; ST R0, #3; LDI R1, #3; LDR R2, R0, #0; HALT
+        // .FILL 0; .FILL x3005
+        memory.write_at(
+            &[
+                0b0001_000_000_1_00101,
+                0b0011_000_000000011,
+                0b1010_001_000000011,
+                0b0110_010_000_000000,
+                HALT,
+                0x1234,
+                0x3005,
+            ],
+            0x3000,
+        );
+        cpu.add_watchpoint(0x3005..=0x3006, WatchKind::Write);
+        cpu.add_watchpoint(0x3006..=0x3006, WatchKind::Read);
+        cpu.add_watchpoint(0x0005..=0x0005, WatchKind::Access);
+
+        assert_eq!(
+            cpu.execute(&mut memory).unwrap(),
+            ExitReason::Watchpoint(WatchpointHit {
+                pc: 0x3001,
+                instr: 0b0011_000_000000011,
+                address: 0x3005,
+                access: WatchKind::Write,
+                old_value: 0x1234,
+                new_value: 5,
+            })
+        );
+        assert_eq!(memory[0x3005], 5);
+
+        // LDI reads the watched pointer first, then the data word
+        let outcome = cpu.step(&mut memory).unwrap();
+        assert!(matches!(
+            outcome,
+            StepOutcome::Watchpoint(WatchpointHit {
+                pc: 0x3002,
+                address: 0x3006,
+                access: WatchKind::Read,
+                old_value: 0x3005,
+                new_value: 0x3005,
+                ..
+            })
+        ));
+        assert_eq!(cpu[Register::R1], 5);
+
+        assert!(matches!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Watchpoint(WatchpointHit {
+                address: 0x0005,
+                access: WatchKind::Read,
+                ..
+            })
+        ));
+
+        assert!(!cpu.remove_watchpoint(&(0x0005..=0x0005), WatchKind::Read));
+        assert!(cpu.remove_watchpoints(&(0x0005..=0x0005)));
+        assert!(!cpu.remove_watchpoints(&(0x0005..=0x0005)));
+        assert!(cpu.remove_watchpoint(&(0x3006..=0x3006), WatchKind::Read));
+        assert_eq!(cpu.watchpoints().len(), 1);
+        assert_eq!(cpu.execute(&mut memory).unwrap(), ExitReason::Halted);
+    }
+
+    #[test]
+    fn step_back_undoes_registers_and_memory_writes() {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        let mut memory = Memory::new();
+        // ADD R0, R0, #5; ST R0, #2; ADD R0, R0, #1; HALT; .FILL x1234
+        memory.write_at(
+            &[
+                0b0001_000_000_1_00101,
+                0b0011_000_000000010,
+                0b0001_000_000_1_00001,
+                HALT,
+                0x1234,
+            ],
+            0x3000,
+        );
+        assert_eq!(cpu.step_back(&mut memory), None);
+
+        cpu.set_history_limit(10);
+        assert_eq!(cpu.execute(&mut memory).unwrap(), ExitReason::Halted);
+        assert_eq!(cpu.history_len(), 4);
+        assert_eq!(
+            cpu.step_back(&mut memory),
+            Some(UndoneStep {
+                outcome: Some(StepOutcome::Halted)
+            })
+        );
+        assert_eq!((cpu[Register::PC], cpu[Register::R0]), (0x3003, 6));
+        cpu.step_back(&mut memory).unwrap();
+        assert_eq!((cpu[Register::PC], cpu[Register::R0]), (0x3002, 5));
+        assert_eq!(memory[0x3004], 5);
+        cpu.step_back(&mut memory).unwrap();
+        assert_eq!(memory[0x3004], 0x1234);
+        cpu.step_back(&mut memory).unwrap();
+        assert_eq!((cpu[Register::PC], cpu[Register::R0]), (0x3000, 0));
+        assert_eq!(cpu.condition(), u16::from(ConditionFlag::ZRO));
+        assert_eq!(cpu.step_back(&mut memory), None);
+
+        // only the most recent steps are kept
+        cpu.set_history_limit(2);
+        cpu.add_watchpoint(0x3004..=0x3004, WatchKind::Write);
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.history_len(), 2);
+        cpu.step_back(&mut memory).unwrap();
+        assert!(matches!(
+            cpu.step_back(&mut memory),
+            Some(UndoneStep {
+                outcome: Some(StepOutcome::Watchpoint(_))
+            })
+        ));
+        assert_eq!(cpu[Register::PC], 0x3001);
+        assert_eq!(cpu.step_back(&mut memory), None);
+    }
+
+    #[test]
+    fn step_back_restores_interrupted_state_and_failed_steps() {
+        let console = BufferConsole::new();
+        let mut cpu = Cpu::with_console(Box::new(console.clone()));
+        let mut memory = Memory::new();
+        cpu.set_history_limit(10);
+        cpu[Register::R6] = 0x3000;
+        memory[INTERRUPT_VECTOR_TABLE + 0x80] = 0x1000;
+        memory[KBSR] = 0x4000;
+        memory[0x3000] = 0xD000;
+        console.push_input(b"k");
+
+        assert!(matches!(
+            cpu.step(&mut memory).unwrap(),
+            StepOutcome::Interrupted { .. }
+        ));
+        assert_eq!(cpu[Register::PC], 0x1000);
+        assert!(cpu.step_back(&mut memory).unwrap().outcome.is_some());
+        assert_eq!(cpu[Register::PC], 0x3000);
+        assert_eq!(cpu[Register::R6], 0x3000);
+        assert_eq!([memory[0x2FFE], memory[0x2FFF]], [0, 0]);
+        // the key stays latched rather than being lost
+        assert_eq!(memory.keyboard(), [0xC000, b'k' as u16]);
+
+        memory[KBSR] = 0;
+        assert!(cpu.step(&mut memory).is_err());
+        assert_eq!(
+            cpu.step_back(&mut memory),
+            Some(UndoneStep { outcome: None })
+        );
+        assert_eq!(cpu[Register::PC], 0x3000);
+    }
+
+    #[test]
+    fn reserved_opcode_is_illegal() {
+        let err = run_err(&[0xD123]);
+        assert!(matches!(
+            err,
+            VmError::IllegalOpcode {
+                pc: 0x3000,
+                instr: 0xD123
+            }
+        ));
+    }
+
+    #[test]
+    fn out_rejects_invalid_character() {
+        // LD R0, #1; TRAP x21; .FILL xD800
+        let err = run_err(&[0b0010_000_000000001, 0xF021, 0xD800]);
+        assert!(matches!(
+            err,
+            VmError::InvalidCharacter {
+                pc: 0x3001,
+                value: 0xD800,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn condition_flag_conversion_rejects_invalid_values() {
+        assert!(matches!(
+            ConditionFlag::try_from(0b010),
+            Ok(ConditionFlag::ZRO)
+        ));
+        assert_eq!(ConditionFlag::try_from(0b011).err(), Some(0b011));
+    }
+
+    #[test]
+    fn opcode_decoding_covers_every_pattern() {
+        for word in 0..16u16 {
+            let opcode = Opcode::from(word << 12);
+            assert_eq!(u16::from(opcode), word);
+        }
+    }
+}
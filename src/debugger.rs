@@ -0,0 +1,946 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, Write},
+    ops::RangeInclusive,
+};
+
+use crate::{
+    asm::parse_word,
+    cpu::{
+        ConditionFlag, Cpu, ExitReason, Opcode, Register, StepOutcome, WatchKind, WatchpointHit,
+    },
+    disasm::disassemble,
+    error::VmError,
+    expr::Expr,
+    memory::Memory,
+};
+
+const HELP: &str = "\
+step [n]                 execute n instructions (s)
+next                     step over JSR, JSRR and TRAP (n)
+continue                 run until a breakpoint or the program ends (c)
+finish                   run until the current subroutine returns
+reverse-step [n]         undo n instructions (rs)
+reverse-continue         run backwards to a breakpoint or watchpoint hit (rc)
+break <loc> [if <cond>]  set a breakpoint (b)
+condition <loc> [cond]   change or clear the condition of a breakpoint
+delete <loc>             remove a breakpoint
+breakpoints              list breakpoints
+watch <loc> [end]        stop when the words <loc>..=<end> are written
+rwatch <loc> [end]       stop when they are read
+awatch <loc> [end]       stop when they are read or written
+unwatch <loc> [end]      remove the watchpoints on <loc>..=<end>
+watchpoints              list watchpoints
+registers                show the registers (r)
+set <reg|loc> <value>    change a register or memory word
+x <loc> [count]          show memory words
+list [loc]               disassemble around PC or <loc> (l)
+quit                     leave the debugger (q)
+
+<loc> is an address (x3000, #12288, 12288) or a label. A condition is an
+expression such as `R3 == x0A && mem[x4000] < 0` over registers, memory, the
+flags N, Z and P, labels and `hits`, the number of times the breakpoint has
+been reached. An empty line repeats the previous command.";
+
+/// Steps the debugger records for reverse execution unless the `Cpu` it is
+/// given already keeps a history.
+pub const HISTORY_LIMIT: usize = 100_000;
+
+/// Why execution stopped and control returned to the debugger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The requested step, next or finish completed.
+    Step,
+    /// PC reached a breakpoint.
+    Breakpoint(u16),
+    /// An instruction touched a watched address.
+    Watchpoint(WatchpointHit),
+    /// The program halted or powered off the machine.
+    Exited(ExitReason),
+    /// Reverse execution used up the recorded history.
+    HistoryStart,
+    /// The front end asked a running program to stop.
+    Interrupted,
+}
+
+/// Runs a program under user control with breakpoints and symbolic
+/// addresses, built on `Cpu::step`.
+pub struct Debugger {
+    cpu: Cpu,
+    memory: Memory,
+    symbols: BTreeMap<String, u16>,
+    breakpoints: BTreeMap<u16, Breakpoint>,
+    exited: Option<ExitReason>,
+}
+
+impl Debugger {
+    pub fn new(mut cpu: Cpu, memory: Memory) -> Self {
+        if cpu.history_limit() == 0 {
+            cpu.set_history_limit(HISTORY_LIMIT);
+        }
+        Self {
+            cpu,
+            memory,
+            symbols: BTreeMap::new(),
+            breakpoints: BTreeMap::new(),
+            exited: None,
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// Makes `symbols` available as locations and in listings.
+    pub fn add_symbols(&mut self, symbols: impl IntoIterator<Item = (String, u16)>) {
+        self.symbols.extend(symbols);
+    }
+
+    pub fn symbol(&self, label: &str) -> Option<u16> {
+        self.symbols.get(label).copied()
+    }
+
+    fn label_at(&self, address: u16) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|(_, &value)| value == address)
+            .map(|(label, _)| label.as_str())
+    }
+
+    /// Returns false if there already was a breakpoint at `address`.
+    pub fn add_breakpoint(&mut self, address: u16) -> bool {
+        if self.breakpoints.contains_key(&address) {
+            return false;
+        }
+        self.breakpoints.insert(address, Breakpoint::default());
+        true
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    /// Makes the breakpoint at `address` stop only when `condition` holds.
+    /// Returns false if there is no breakpoint there.
+    pub fn set_condition(&mut self, address: u16, condition: Option<Expr>) -> bool {
+        match self.breakpoints.get_mut(&address) {
+            Some(breakpoint) => {
+                breakpoint.condition = condition;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How many times execution has reached the breakpoint at `address`,
+    /// whether or not its condition held.
+    pub fn hits(&self, address: u16) -> Option<u64> {
+        self.breakpoints
+            .get(&address)
+            .map(|breakpoint| breakpoint.hits)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.keys().copied()
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> Result<Stop, VmError> {
+        self.run(|_| true)
+    }
+
+    /// Like `step`, but runs a subroutine, trap or interrupt entered by the
+    /// instruction to completion.
+    pub fn step_over(&mut self) -> Result<Stop, VmError> {
+        let mut depth = 0;
+        self.run(|change| {
+            depth += change;
+            depth <= 0
+        })
+    }
+
+    /// Runs until a breakpoint is reached or the program ends.
+    pub fn resume(&mut self) -> Result<Stop, VmError> {
+        self.run(|_| false)
+    }
+
+    /// Like `resume`, but also stops once `interrupted` returns true. It is
+    /// asked after every instruction.
+    pub fn resume_until(&mut self, mut interrupted: impl FnMut() -> bool) -> Result<Stop, VmError> {
+        match self.run(|_| interrupted())? {
+            Stop::Step => Ok(Stop::Interrupted),
+            stop => Ok(stop),
+        }
+    }
+
+    /// Runs until the current subroutine, trap or interrupt returns.
+    pub fn finish(&mut self) -> Result<Stop, VmError> {
+        let mut depth = 0;
+        self.run(|change| {
+            depth += change;
+            depth < 0
+        })
+    }
+
+    /// Undoes the most recently executed instruction.
+    pub fn reverse_step(&mut self) -> Stop {
+        match self.cpu.step_back(&mut self.memory) {
+            Some(_) => {
+                self.exited = None;
+                Stop::Step
+            }
+            None => Stop::HistoryStart,
+        }
+    }
+
+    /// Undoes instructions until PC is back at a breakpoint whose condition
+    /// holds or an instruction that hit a watchpoint has been undone.
+    /// Breakpoint hit counts are left as they are.
+    pub fn reverse_continue(&mut self) -> Stop {
+        loop {
+            let undone = match self.cpu.step_back(&mut self.memory) {
+                Some(undone) => undone,
+                None => return Stop::HistoryStart,
+            };
+            self.exited = None;
+            if let Some(StepOutcome::Watchpoint(hit)) = undone.outcome {
+                return Stop::Watchpoint(hit);
+            }
+
+            let pc = self.cpu[Register::PC];
+            if let Some(breakpoint) = self.breakpoints.get(&pc) {
+                let stop = match &breakpoint.condition {
+                    Some(condition) => condition.is_true(&self.cpu, &self.memory, breakpoint.hits),
+                    None => true,
+                };
+                if stop {
+                    return Stop::Breakpoint(pc);
+                }
+            }
+        }
+    }
+
+    /// Steps until `done` accepts the call depth change of an instruction or
+    /// a breakpoint or watchpoint is reached. At least one instruction always executes, so
+    /// resuming from a breakpoint moves past it.
+    fn run(&mut self, mut done: impl FnMut(i32) -> bool) -> Result<Stop, VmError> {
+        if let Some(reason) = self.exited {
+            return Ok(Stop::Exited(reason));
+        }
+
+        loop {
+            let pc = self.cpu[Register::PC];
+            let instr = self.memory[pc];
+            let outcome = match self.cpu.step(&mut self.memory)? {
+                StepOutcome::Halted => return Ok(self.exit(ExitReason::Halted)),
+                StepOutcome::PoweredOff => {
+                    return Ok(self.exit(ExitReason::powered_off(&self.memory)))
+                }
+                StepOutcome::Watchpoint(hit) => return Ok(Stop::Watchpoint(hit)),
+                outcome => outcome,
+            };
+
+            if done(self.call_depth_change(pc, instr, outcome)) {
+                return Ok(Stop::Step);
+            }
+            let pc = self.cpu[Register::PC];
+            if let Some(breakpoint) = self.breakpoints.get_mut(&pc) {
+                breakpoint.hits += 1;
+                let stop = match &breakpoint.condition {
+                    Some(condition) => condition.is_true(&self.cpu, &self.memory, breakpoint.hits),
+                    None => true,
+                };
+                if stop {
+                    return Ok(Stop::Breakpoint(pc));
+                }
+            }
+        }
+    }
+
+    fn exit(&mut self, reason: ExitReason) -> Stop {
+        self.exited = Some(reason);
+        Stop::Exited(reason)
+    }
+
+    /// +1 when the step entered a subroutine or service routine, -1 when it
+    /// returned from one.
+    fn call_depth_change(&self, pc: u16, instr: u16, outcome: StepOutcome) -> i32 {
+        match outcome {
+            StepOutcome::Exception { .. } | StepOutcome::Interrupted { .. } => 1,
+            // traps run natively finish within the step
+            StepOutcome::Trapped { .. } => (self.cpu[Register::PC] != pc.wrapping_add(1)) as i32,
+            _ => match Opcode::from(instr) {
+                Opcode::JSR => 1,
+                Opcode::JMP if (instr >> 6) & 0b111 == 7 => -1,
+                Opcode::RTI => -1,
+                _ => 0,
+            },
+        }
+    }
+
+    /// Reads commands from `input` until it ends or `quit` is entered,
+    /// writing responses to `output`.
+    pub fn repl(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        self.show_location(&mut output)?;
+        let mut previous = String::new();
+        loop {
+            write!(output, "(lc3) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+            let line = match line.trim() {
+                "" => previous.clone(),
+                line => line.to_string(),
+            };
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match self.command(&words, &mut output) {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Quit) => return Ok(()),
+                Err(Failure::Usage(message)) => writeln!(output, "{}", message)?,
+                Err(Failure::Io(err)) => return Err(err),
+            }
+            previous = line;
+        }
+    }
+
+    fn command(&mut self, words: &[&str], output: &mut impl Write) -> Result<Flow, Failure> {
+        let (&name, args) = match words.split_first() {
+            Some(split) => split,
+            None => return Ok(Flow::Continue),
+        };
+        match name {
+            "s" | "step" => {
+                let count = count(args, "usage: step [n]")?;
+                let mut stop = Ok(Stop::Step);
+                for _ in 0..count {
+                    stop = self.step();
+                    if !matches!(stop, Ok(Stop::Step)) {
+                        break;
+                    }
+                }
+                self.report(stop, output)?;
+            }
+            "n" | "next" => {
+                let stop = self.step_over();
+                self.report(stop, output)?;
+            }
+            "c" | "continue" => {
+                let stop = self.resume();
+                self.report(stop, output)?;
+            }
+            "finish" => {
+                let stop = self.finish();
+                self.report(stop, output)?;
+            }
+            "rs" | "reverse-step" => {
+                let mut stop = Stop::Step;
+                for _ in 0..count(args, "usage: reverse-step [n]")? {
+                    stop = self.reverse_step();
+                    if stop != Stop::Step {
+                        break;
+                    }
+                }
+                self.report(Ok(stop), output)?;
+            }
+            "rc" | "reverse-continue" => {
+                let stop = self.reverse_continue();
+                self.report(Ok(stop), output)?;
+            }
+            "b" | "break" => {
+                let (address, condition) = match args {
+                    [loc] => (self.location(loc)?, None),
+                    [loc, "if", condition @ ..] if !condition.is_empty() => {
+                        (self.location(loc)?, Some(self.condition(condition)?))
+                    }
+                    _ => return Err(Failure::usage("usage: break <loc> [if <cond>]")),
+                };
+                let added = self.add_breakpoint(address);
+                if added || condition.is_some() {
+                    self.set_condition(address, condition);
+                }
+                if added {
+                    writeln!(output, "breakpoint at {}", self.describe(address))?;
+                } else {
+                    writeln!(
+                        output,
+                        "breakpoint already set at {}",
+                        self.describe(address)
+                    )?;
+                }
+            }
+            "delete" => {
+                let address = self.location(one_arg(args, "usage: delete <loc>")?)?;
+                if !self.remove_breakpoint(address) {
+                    return Err(Failure::usage(format!(
+                        "no breakpoint at {}",
+                        self.describe(address)
+                    )));
+                }
+            }
+            "condition" => {
+                let (loc, condition) = match args.split_first() {
+                    Some((loc, [])) => (loc, None),
+                    Some((loc, condition)) => (loc, Some(self.condition(condition)?)),
+                    None => return Err(Failure::usage("usage: condition <loc> [cond]")),
+                };
+                let address = self.location(loc)?;
+                if !self.set_condition(address, condition) {
+                    return Err(Failure::usage(format!(
+                        "no breakpoint at {}",
+                        self.describe(address)
+                    )));
+                }
+            }
+            "breakpoints" => {
+                if self.breakpoints.is_empty() {
+                    writeln!(output, "no breakpoints")?;
+                }
+                for (&address, breakpoint) in &self.breakpoints {
+                    write!(output, "{}", self.describe(address))?;
+                    if let Some(condition) = &breakpoint.condition {
+                        write!(output, " if {}", condition)?;
+                    }
+                    writeln!(output, ", reached {} times", breakpoint.hits)?;
+                }
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match name {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let range = self.range(args, name)?;
+                writeln!(output, "watchpoint on {}", self.describe_range(&range))?;
+                self.cpu.add_watchpoint(range, kind);
+            }
+            "unwatch" => {
+                let range = self.range(args, name)?;
+                if !self.cpu.remove_watchpoints(&range) {
+                    return Err(Failure::usage(format!(
+                        "no watchpoint on {}",
+                        self.describe_range(&range)
+                    )));
+                }
+            }
+            "watchpoints" => {
+                if self.cpu.watchpoints().is_empty() {
+                    writeln!(output, "no watchpoints")?;
+                }
+                for watchpoint in self.cpu.watchpoints() {
+                    let kind = match watchpoint.kind {
+                        WatchKind::Read => "read",
+                        WatchKind::Write => "write",
+                        WatchKind::Access => "access",
+                    };
+                    writeln!(
+                        output,
+                        "{} {}",
+                        kind,
+                        self.describe_range(&watchpoint.range)
+                    )?;
+                }
+            }
+            "r" | "registers" => self.show_registers(output)?,
+            "set" => {
+                let [target, value] = args else {
+                    return Err(Failure::usage("usage: set <reg|loc> <value>"));
+                };
+                let value = parse_word(value)
+                    .ok_or_else(|| Failure::usage(format!("invalid value: {}", value)))?;
+                match register(target) {
+                    Some(register) => self.cpu[register] = value,
+                    None => {
+                        let address = self.location(target)?;
+                        self.memory[address] = value;
+                    }
+                }
+            }
+            "x" => {
+                let (address, count) = match args {
+                    [loc] => (self.location(loc)?, 1),
+                    [loc, count] => (
+                        self.location(loc)?,
+                        count
+                            .parse()
+                            .map_err(|_| Failure::usage(format!("invalid count: {}", count)))?,
+                    ),
+                    _ => return Err(Failure::usage("usage: x <loc> [count]")),
+                };
+                for offset in 0..count {
+                    let address = address.wrapping_add(offset);
+                    let value = self.memory[address];
+                    writeln!(output, "x{:04X}  x{:04X}  {}", address, value, value as i16)?;
+                }
+            }
+            "l" | "list" => {
+                let center = match args {
+                    [] => self.cpu[Register::PC],
+                    [loc] => self.location(loc)?,
+                    _ => return Err(Failure::usage("usage: list [loc]")),
+                };
+                for offset in -4i16..=5 {
+                    let address = center.wrapping_add(offset as u16);
+                    self.show_instruction(address, output)?;
+                }
+            }
+            "help" => writeln!(output, "{}", HELP)?,
+            "q" | "quit" => return Ok(Flow::Quit),
+            _ => {
+                return Err(Failure::usage(format!(
+                    "unknown command: {} (try help)",
+                    name
+                )))
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn report(&self, stop: Result<Stop, VmError>, output: &mut impl Write) -> io::Result<()> {
+        match stop {
+            Ok(Stop::Step) => {}
+            Ok(Stop::Breakpoint(address)) => {
+                writeln!(output, "breakpoint at {}", self.describe(address))?
+            }
+            Ok(Stop::Watchpoint(hit)) => {
+                let access = match hit.access {
+                    WatchKind::Write => "written",
+                    _ => "read",
+                };
+                write!(
+                    output,
+                    "watchpoint: {} {} by x{:04X} ({}): ",
+                    self.describe(hit.address),
+                    access,
+                    hit.pc,
+                    disassemble(hit.pc, hit.instr)
+                )?;
+                if hit.access == WatchKind::Write {
+                    writeln!(output, "x{:04X} -> x{:04X}", hit.old_value, hit.new_value)?
+                } else {
+                    writeln!(output, "x{:04X}", hit.new_value)?
+                }
+            }
+            Ok(Stop::Exited(ExitReason::Halted)) => {
+                return writeln!(output, "program halted");
+            }
+            Ok(Stop::Exited(ExitReason::Faulted)) => {
+                return writeln!(output, "machine stopped after a fault");
+            }
+            Ok(Stop::Exited(_)) => return writeln!(output, "machine powered off"),
+            Ok(Stop::HistoryStart) => writeln!(output, "no earlier history")?,
+            Ok(Stop::Interrupted) => writeln!(output, "interrupted")?,
+            Err(err) => writeln!(output, "error: {}", err)?,
+        }
+        self.show_location(output)
+    }
+
+    fn show_location(&self, output: &mut impl Write) -> io::Result<()> {
+        self.show_instruction(self.cpu[Register::PC], output)
+    }
+
+    fn show_instruction(&self, address: u16, output: &mut impl Write) -> io::Result<()> {
+        if let Some(label) = self.label_at(address) {
+            writeln!(output, "{}:", label)?;
+        }
+        let marker = if address == self.cpu[Register::PC] {
+            "=>"
+        } else {
+            "  "
+        };
+        let instr = self.memory[address];
+        writeln!(
+            output,
+            "{} x{:04X}  x{:04X}  {}",
+            marker,
+            address,
+            instr,
+            disassemble(address, instr)
+        )
+    }
+
+    fn show_registers(&self, output: &mut impl Write) -> io::Result<()> {
+        for row in 0..2 {
+            let line: Vec<String> = (row * 4..row * 4 + 4)
+                .map(|r| format!("R{} x{:04X}", r, self.cpu[r]))
+                .collect();
+            writeln!(output, "{}", line.join("  "))?;
+        }
+        let condition = match ConditionFlag::try_from(self.cpu.condition()) {
+            Ok(ConditionFlag::NEG) => "N",
+            Ok(ConditionFlag::ZRO) => "Z",
+            Ok(ConditionFlag::POS) => "P",
+            Err(_) => "?",
+        };
+        let mode = if self.cpu.user_mode() {
+            "user"
+        } else {
+            "supervisor"
+        };
+        writeln!(
+            output,
+            "PC x{:04X}  PSR x{:04X}  CC {}  {} mode",
+            self.cpu[Register::PC],
+            self.cpu[Register::PSR],
+            condition,
+            mode
+        )
+    }
+
+    fn describe(&self, address: u16) -> String {
+        match self.label_at(address) {
+            Some(label) => format!("x{:04X} ({})", address, label),
+            None => format!("x{:04X}", address),
+        }
+    }
+
+    fn describe_range(&self, range: &RangeInclusive<u16>) -> String {
+        if range.start() == range.end() {
+            self.describe(*range.start())
+        } else {
+            format!(
+                "{}..={}",
+                self.describe(*range.start()),
+                self.describe(*range.end())
+            )
+        }
+    }
+
+    /// Parses the `<loc> [end]` arguments of the watch commands.
+    fn range(&self, args: &[&str], name: &str) -> Result<RangeInclusive<u16>, Failure> {
+        let (start, end) = match args {
+            [loc] => {
+                let start = self.location(loc)?;
+                (start, start)
+            }
+            [loc, end] => (self.location(loc)?, self.location(end)?),
+            _ => return Err(Failure::usage(format!("usage: {} <loc> [end]", name))),
+        };
+        if start > end {
+            return Err(Failure::usage("the range ends before it starts"));
+        }
+        Ok(start..=end)
+    }
+
+    fn condition(&self, words: &[&str]) -> Result<Expr, Failure> {
+        Expr::parse(&words.join(" "), &self.symbols)
+            .map_err(|err| Failure::usage(format!("invalid condition: {}", err)))
+    }
+
+    /// Resolves an address or label.
+    fn location(&self, text: &str) -> Result<u16, Failure> {
+        parse_word(text)
+            .or_else(|| self.symbol(text))
+            .ok_or_else(|| Failure::usage(format!("unknown location: {}", text)))
+    }
+}
+
+#[derive(Default)]
+struct Breakpoint {
+    condition: Option<Expr>,
+    hits: u64,
+}
+
+enum Flow {
+    Continue,
+    Quit,
+}
+
+/// A command failed: either it was mistyped and the REPL goes on, or the
+/// output could not be written.
+enum Failure {
+    Usage(String),
+    Io(io::Error),
+}
+
+impl Failure {
+    fn usage(message: impl Into<String>) -> Self {
+        Failure::Usage(message.into())
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Io(err)
+    }
+}
+
+fn one_arg<'a>(args: &[&'a str], usage: &str) -> Result<&'a str, Failure> {
+    match args {
+        [arg] => Ok(arg),
+        _ => Err(Failure::usage(usage)),
+    }
+}
+
+/// Parses the optional repeat count of `step` and `reverse-step`.
+fn count(args: &[&str], usage: &str) -> Result<u32, Failure> {
+    match args {
+        [] => Ok(1),
+        [count] => count
+            .parse()
+            .map_err(|_| Failure::usage(format!("invalid count: {}", count))),
+        _ => Err(Failure::usage(usage)),
+    }
+}
+
+fn register(name: &str) -> Option<Register> {
+    Some(match name.to_ascii_uppercase().as_str() {
+        "R0" => Register::R0,
+        "R1" => Register::R1,
+        "R2" => Register::R2,
+        "R3" => Register::R3,
+        "R4" => Register::R4,
+        "R5" => Register::R5,
+        "R6" => Register::R6,
+        "R7" => Register::R7,
+        "PC" => Register::PC,
+        "PSR" => Register::PSR,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, console::BufferConsole};
+
+    const PROGRAM: &str = "
+        .ORIG x3000
+        AND R1, R1, #0
+        JSR DOUBLE
+LOOP    ADD R1, R1, #1
+        ADD R2, R1, #-3
+        BRn LOOP
+        HALT
+DOUBLE  ADD R1, R1, R1
+        RET
+        .END";
+
+    fn debugger() -> Debugger {
+        debugger_for(PROGRAM)
+    }
+
+    fn debugger_for(source: &str) -> Debugger {
+        let program = assemble(source).unwrap();
+        let mut memory = Memory::new();
+        program.image().load_into(&mut memory);
+        let mut debugger = Debugger::new(Cpu::with_console(Box::new(BufferConsole::new())), memory);
+        debugger.add_symbols(program.symbols().clone());
+        debugger
+    }
+
+    fn session(debugger: &mut Debugger, commands: &str) -> String {
+        let mut output = Vec::new();
+        debugger.repl(commands.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn next_steps_over_subroutine_calls() {
+        let mut debugger = debugger();
+        debugger.step().unwrap();
+        assert_eq!(debugger.step_over().unwrap(), Stop::Step);
+        assert_eq!(debugger.cpu()[Register::PC], 0x3002);
+
+        debugger.step().unwrap();
+        assert_eq!(debugger.step_over().unwrap(), Stop::Step);
+        assert_eq!(debugger.cpu()[Register::PC], 0x3004);
+    }
+
+    #[test]
+    fn finish_runs_until_the_subroutine_returns() {
+        let mut debugger = debugger();
+        debugger.step().unwrap();
+        debugger.step().unwrap();
+        assert_eq!(debugger.cpu()[Register::PC], 0x3006);
+        assert_eq!(debugger.finish().unwrap(), Stop::Step);
+        assert_eq!(debugger.cpu()[Register::PC], 0x3002);
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoints_until_the_program_halts() {
+        let mut debugger = debugger();
+        let target = debugger.symbol("LOOP").unwrap();
+        debugger.add_breakpoint(target);
+
+        for _ in 0..3 {
+            assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(target));
+        }
+        assert_eq!(debugger.resume().unwrap(), Stop::Exited(ExitReason::Halted));
+        assert_eq!(debugger.cpu()[Register::R1], 3);
+        assert_eq!(debugger.step().unwrap(), Stop::Exited(ExitReason::Halted));
+    }
+
+    #[test]
+    fn repl_sets_breakpoints_by_label_and_shows_state() {
+        let mut debugger = debugger();
+        let output = session(
+            &mut debugger,
+            "break LOOP\ncontinue\n\nregisters\nset R1 #-2\nset x4000 x00FF\nx x4000\nx LOOP 2\n",
+        );
+
+        assert_eq!(
+            output,
+            "=> x3000  x5260  AND R1, R1, #0
+(lc3) breakpoint at x3002 (LOOP)
+(lc3) breakpoint at x3002 (LOOP)
+LOOP:
+=> x3002  x1261  ADD R1, R1, #1
+(lc3) breakpoint at x3002 (LOOP)
+LOOP:
+=> x3002  x1261  ADD R1, R1, #1
+(lc3) R0 x0000  R1 x0001  R2 xFFFE  R3 x0000
+R4 x0000  R5 x0000  R6 x0000  R7 x3002
+PC x3002  PSR x0004  CC N  supervisor mode
+(lc3) (lc3) (lc3) x4000  x00FF  255
+(lc3) x3002  x1261  4705
+x3003  x147D  5245
+(lc3) \n"
+        );
+        assert_eq!(debugger.cpu()[Register::R1], 0xFFFE);
+    }
+
+    #[test]
+    fn conditional_breakpoints_stop_only_when_the_condition_holds() {
+        let mut debugger = debugger();
+        let output = session(
+            &mut debugger,
+            "break LOOP if R1 == 1\nc\ncondition LOOP hits == 3 || mem[x3009] < 0\nbreak LOOP\nbreakpoints\n\
+             c\nbreak DOUBLE if R9\ncondition x3000 N\nc\nbreakpoints\n",
+        );
+
+        assert_eq!(
+            output,
+            "=> x3000  x5260  AND R1, R1, #0
+(lc3) breakpoint at x3002 (LOOP)
+(lc3) breakpoint at x3002 (LOOP)
+LOOP:
+=> x3002  x1261  ADD R1, R1, #1
+(lc3) (lc3) breakpoint already set at x3002 (LOOP)
+(lc3) x3002 (LOOP) if hits == 3 || mem[x3009] < 0, reached 2 times
+(lc3) breakpoint at x3002 (LOOP)
+LOOP:
+=> x3002  x1261  ADD R1, R1, #1
+(lc3) invalid condition: column 1: unknown name: R9
+(lc3) no breakpoint at x3000
+(lc3) program halted
+(lc3) x3002 (LOOP) if hits == 3 || mem[x3009] < 0, reached 3 times
+(lc3) \n"
+        );
+        assert_eq!(debugger.hits(0x3002), Some(3));
+    }
+
+    #[test]
+    fn repl_stops_at_watchpoints() {
+        let mut debugger = debugger_for(
+            ".ORIG x3000
+            LD R0, COUNT
+            ADD R0, R0, #1
+            ST R0, COUNT
+            HALT
+    COUNT   .FILL #41
+            .END",
+        );
+
+        let output = session(
+            &mut debugger,
+            "awatch COUNT\nwatchpoints\nc\nc\nunwatch COUNT\nc\n",
+        );
+        assert_eq!(
+            output,
+            "=> x3000  x2003  LD R0, x3004
+(lc3) watchpoint on x3004 (COUNT)
+(lc3) access x3004 (COUNT)
+(lc3) watchpoint: x3004 (COUNT) read by x3000 (LD R0, x3004): x0029
+=> x3001  x1021  ADD R0, R0, #1
+(lc3) watchpoint: x3004 (COUNT) written by x3002 (ST R0, x3004): x0029 -> x002A
+=> x3003  xF025  HALT
+(lc3) (lc3) program halted
+(lc3) \n"
+        );
+    }
+
+    #[test]
+    fn reverse_continue_goes_back_to_earlier_breakpoint_hits() {
+        let mut debugger = debugger();
+        let target = debugger.symbol("LOOP").unwrap();
+        debugger.add_breakpoint(target);
+        for _ in 0..3 {
+            debugger.resume().unwrap();
+        }
+        assert_eq!(debugger.resume().unwrap(), Stop::Exited(ExitReason::Halted));
+
+        assert_eq!(debugger.reverse_continue(), Stop::Breakpoint(target));
+        assert_eq!(debugger.cpu()[Register::R1], 2);
+        assert_eq!(debugger.reverse_continue(), Stop::Breakpoint(target));
+        assert_eq!(debugger.cpu()[Register::R1], 1);
+        assert_eq!(debugger.reverse_step(), Stop::Step);
+        assert_eq!(debugger.cpu()[Register::PC], 0x3004);
+        assert_eq!(debugger.hits(target), Some(3));
+
+        debugger.remove_breakpoint(target);
+        assert_eq!(debugger.reverse_continue(), Stop::HistoryStart);
+        assert_eq!(debugger.cpu()[Register::PC], 0x3000);
+        assert_eq!(debugger.reverse_step(), Stop::HistoryStart);
+        assert_eq!(debugger.resume().unwrap(), Stop::Exited(ExitReason::Halted));
+        assert_eq!(debugger.cpu()[Register::R1], 3);
+    }
+
+    #[test]
+    fn repl_reverses_to_watchpoint_hits() {
+        let mut debugger = debugger_for(
+            ".ORIG x3000
+            LD R0, COUNT
+            ADD R0, R0, #1
+            ST R0, COUNT
+            HALT
+    COUNT   .FILL #41
+            .END",
+        );
+
+        let output = session(&mut debugger, "watch COUNT\nc\nc\nrc\nrs 5\nx COUNT\n");
+        assert_eq!(
+            output,
+            "=> x3000  x2003  LD R0, x3004
+(lc3) watchpoint on x3004 (COUNT)
+(lc3) watchpoint: x3004 (COUNT) written by x3002 (ST R0, x3004): x0029 -> x002A
+=> x3003  xF025  HALT
+(lc3) program halted
+(lc3) watchpoint: x3004 (COUNT) written by x3002 (ST R0, x3004): x0029 -> x002A
+=> x3002  x3001  ST R0, x3004
+(lc3) no earlier history
+=> x3000  x2003  LD R0, x3004
+(lc3) x3004  x0029  41
+(lc3) \n"
+        );
+    }
+
+    #[test]
+    fn repl_lists_around_pc_and_reports_mistakes() {
+        let mut debugger = debugger();
+        let output = session(&mut debugger, "step 2\nlist\nbreak NOWHERE\nfly\nq\n");
+
+        assert!(output.contains("DOUBLE:\n=> x3006  x1241  ADD R1, R1, R1\n"));
+        assert!(output.contains("   x3007  xC1C0  RET\n"));
+        assert!(output.contains("unknown location: NOWHERE\n"));
+        assert!(output.contains("unknown command: fly (try help)\n"));
+        assert!(output.ends_with("(lc3) "));
+    }
+}
@@ -0,0 +1,179 @@
+use std::ops::RangeInclusive;
+
+use crate::{
+    cpu::{sign_extend, Opcode, Trapcode},
+    memory::Memory,
+};
+
+/// Renders a single instruction word as LC-3 assembly. PC-relative operands
+/// are shown as the absolute address they resolve to from `address`.
+pub fn disassemble(address: u16, instr: u16) -> String {
+    let dr = (instr >> 9) & 0b111;
+    let sr1 = (instr >> 6) & 0b111;
+    let next_pc = address.wrapping_add(1);
+    let target = |bits: u32| {
+        let offset = sign_extend(instr & ((1 << bits) - 1), bits);
+        next_pc.wrapping_add(offset)
+    };
+
+    match Opcode::from(instr) {
+        Opcode::BR => {
+            let cond = (instr >> 9) & 0b111;
+            if cond == 0 {
+                return "NOP".to_string();
+            }
+            let mut mnemonic = String::from("BR");
+            for (bit, flag) in [(0b100, 'n'), (0b010, 'z'), (0b001, 'p')] {
+                if cond & bit != 0 {
+                    mnemonic.push(flag);
+                }
+            }
+            format!("{} x{:04X}", mnemonic, target(9))
+        }
+        Opcode::ADD | Opcode::AND => {
+            let mnemonic = if let Opcode::ADD = Opcode::from(instr) {
+                "ADD"
+            } else {
+                "AND"
+            };
+            if (instr >> 5) & 0b1 == 0 {
+                format!("{} R{}, R{}, R{}", mnemonic, dr, sr1, instr & 0b111)
+            } else {
+                let imm = sign_extend(instr & 0x1F, 5) as i16;
+                format!("{} R{}, R{}, #{}", mnemonic, dr, sr1, imm)
+            }
+        }
+        Opcode::LD => format!("LD R{}, x{:04X}", dr, target(9)),
+        Opcode::ST => format!("ST R{}, x{:04X}", dr, target(9)),
+        Opcode::LDI => format!("LDI R{}, x{:04X}", dr, target(9)),
+        Opcode::STI => format!("STI R{}, x{:04X}", dr, target(9)),
+        Opcode::LEA => format!("LEA R{}, x{:04X}", dr, target(9)),
+        Opcode::JSR => {
+            if (instr >> 11) & 0b1 == 1 {
+                format!("JSR x{:04X}", target(11))
+            } else {
+                format!("JSRR R{}", sr1)
+            }
+        }
+        Opcode::LDR | Opcode::STR => {
+            let mnemonic = if let Opcode::LDR = Opcode::from(instr) {
+                "LDR"
+            } else {
+                "STR"
+            };
+            let offset = sign_extend(instr & 0x3F, 6) as i16;
+            format!("{} R{}, R{}, #{}", mnemonic, dr, sr1, offset)
+        }
+        Opcode::RTI => "RTI".to_string(),
+        Opcode::NOT => format!("NOT R{}, R{}", dr, sr1),
+        Opcode::JMP => {
+            if sr1 == 7 {
+                "RET".to_string()
+            } else {
+                format!("JMP R{}", sr1)
+            }
+        }
+        Opcode::RES => format!(".FILL x{:04X}", instr),
+        Opcode::TRAP => {
+            let vector = instr & 0xFF;
+            match Trapcode::try_from(vector) {
+                Ok(Trapcode::GETC) => "GETC".to_string(),
+                Ok(Trapcode::OUT) => "OUT".to_string(),
+                Ok(Trapcode::PUTS) => "PUTS".to_string(),
+                Ok(Trapcode::IN) => "IN".to_string(),
+                Ok(Trapcode::PUTSP) => "PUTSP".to_string(),
+                Ok(Trapcode::HALT) => "HALT".to_string(),
+                Err(_) => format!("TRAP x{:02X}", vector),
+            }
+        }
+    }
+}
+
+/// Renders every word in `range` as one listing line of address, raw word
+/// and assembly.
+pub fn disasm(memory: &Memory, range: RangeInclusive<u16>) -> String {
+    let mut listing = String::new();
+    for address in range {
+        let instr = memory[address];
+        listing.push_str(&format!(
+            "x{:04X}  x{:04X}  {}\n",
+            address,
+            instr,
+            disassemble(address, instr)
+        ));
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn renders_every_opcode() {
+        let cases = [
+            (0x1283, "ADD R1, R2, R3"),
+            (0x12B0, "ADD R1, R2, #-16"),
+            (0x5E2F, "AND R7, R0, #15"),
+            (0x997F, "NOT R4, R5"),
+            (0x0E02, "BRnzp x3003"),
+            (0x09FF, "BRn x3000"),
+            (0x0000, "NOP"),
+            (0xC080, "JMP R2"),
+            (0xC1C0, "RET"),
+            (0x4FFF, "JSR x3000"),
+            (0x40C0, "JSRR R3"),
+            (0x2201, "LD R1, x3002"),
+            (0xA5FF, "LDI R2, x3000"),
+            (0x6720, "LDR R3, R4, #-32"),
+            (0xEA00, "LEA R5, x3001"),
+            (0x3C00, "ST R6, x3001"),
+            (0xBE00, "STI R7, x3001"),
+            (0x705F, "STR R0, R1, #31"),
+            (0x8000, "RTI"),
+            (0xD123, ".FILL xD123"),
+            (0xF026, "TRAP x26"),
+            (0xF020, "GETC"),
+            (0xF021, "OUT"),
+            (0xF022, "PUTS"),
+            (0xF023, "IN"),
+            (0xF024, "PUTSP"),
+            (0xF025, "HALT"),
+        ];
+
+        for (instr, text) in cases {
+            assert_eq!(disassemble(0x3000, instr), text, "{:#06x}", instr);
+        }
+    }
+
+    #[test]
+    fn targets_wrap_around_memory() {
+        assert_eq!(disassemble(0x0000, 0x0FFE), "BRnzp xFFFF");
+        assert_eq!(disassemble(0xFFFF, 0x2200), "LD R1, x0000");
+    }
+
+    #[test]
+    fn lists_assembled_program() {
+        let source = ".ORIG x3000
+            LOOP LEA R0, MSG
+                 PUTS
+                 ADD R1, R1, #-1
+                 BRp LOOP
+                 HALT
+            MSG  .FILL x0
+                 .END";
+        let program = assemble(source).unwrap();
+        let mut memory = Memory::new();
+        program.image().load_into(&mut memory);
+
+        assert_eq!(
+            disasm(&memory, 0x3000..=0x3004),
+            "x3000  xE004  LEA R0, x3005\n\
+             x3001  xF022  PUTS\n\
+             x3002  x127F  ADD R1, R1, #-1\n\
+             x3003  x03FC  BRp x3000\n\
+             x3004  xF025  HALT\n"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::{error, fmt, io};
+
+#[derive(Debug)]
+pub enum VmError {
+    IllegalOpcode {
+        pc: u16,
+        instr: u16,
+    },
+    PrivilegeViolation {
+        pc: u16,
+        instr: u16,
+    },
+    AccessViolation {
+        pc: u16,
+        instr: u16,
+    },
+    UnknownTrap {
+        pc: u16,
+        instr: u16,
+        vector: u8,
+    },
+    Io {
+        pc: u16,
+        instr: u16,
+        source: io::Error,
+    },
+    InvalidCharacter {
+        pc: u16,
+        instr: u16,
+        value: u16,
+    },
+}
+
+impl VmError {
+    /// Address of the instruction that faulted.
+    pub fn pc(&self) -> u16 {
+        match self {
+            VmError::IllegalOpcode { pc, .. }
+            | VmError::PrivilegeViolation { pc, .. }
+            | VmError::AccessViolation { pc, .. }
+            | VmError::UnknownTrap { pc, .. }
+            | VmError::Io { pc, .. }
+            | VmError::InvalidCharacter { pc, .. } => *pc,
+        }
+    }
+
+    /// The instruction word that faulted.
+    pub fn instr(&self) -> u16 {
+        match self {
+            VmError::IllegalOpcode { instr, .. }
+            | VmError::PrivilegeViolation { instr, .. }
+            | VmError::AccessViolation { instr, .. }
+            | VmError::UnknownTrap { instr, .. }
+            | VmError::Io { instr, .. }
+            | VmError::InvalidCharacter { instr, .. } => *instr,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::IllegalOpcode { .. } => write!(f, "illegal opcode"),
+            VmError::PrivilegeViolation { .. } => write!(f, "privilege mode violation"),
+            VmError::AccessViolation { .. } => write!(f, "access control violation"),
+            VmError::UnknownTrap { vector, .. } => write!(f, "unknown trap vector {:#04x}", vector),
+            VmError::Io { source, .. } => write!(f, "I/O failure: {}", source),
+            VmError::InvalidCharacter { value, .. } => {
+                write!(f, "invalid character {:#06x}", value)
+            }
+        }?;
+
+        write!(
+            f,
+            " at {:#06x} (instruction {:#06x})",
+            self.pc(),
+            self.instr()
+        )
+    }
+}
+
+impl error::Error for VmError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            VmError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use std::{collections::BTreeMap, error, fmt};
+
+use crate::{
+    asm,
+    cpu::{ConditionFlag, Cpu, Register},
+    memory::Memory,
+};
+
+/// A condition such as `R3 == x0A && mem[x4000] < 0`, evaluated against the
+/// machine whenever a breakpoint is reached.
+///
+/// Operands are registers (`R0`-`R7`, `PC`, `PSR`), the condition flags `N`,
+/// `Z` and `P` (1 when set), `hits` (how often the breakpoint has been
+/// reached, this time included), `mem[...]`, labels and numbers written as
+/// in assembly (`x0A`, `0x0A`, `#-3`, `10`). From lowest to highest
+/// precedence the operators are `||`, `&&`, the comparisons `==` `!=` `<`
+/// `<=` `>` `>=`, bitwise `&`, `+` and `-`, and the unary `!`, `-` and `~`.
+/// Values are 16-bit words; ordering comparisons treat them as signed and
+/// any non-zero value is true.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    /// Parses `source`, resolving labels through `symbols`.
+    pub fn parse(source: &str, symbols: &BTreeMap<String, u16>) -> Result<Self, ExprError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            next: 0,
+            end: source.len(),
+            symbols,
+        };
+        let root = parser.or()?;
+        if let Some(token) = tokens.get(parser.next) {
+            return Err(ExprError::new(token.offset, "expected an operator"));
+        }
+        Ok(Self {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    /// Evaluates the expression. Memory is inspected directly, so reading
+    /// device registers has no side effects.
+    pub fn eval(&self, cpu: &Cpu, memory: &Memory, hits: u64) -> u16 {
+        self.root.eval(&Context { cpu, memory, hits })
+    }
+
+    pub fn is_true(&self, cpu: &Cpu, memory: &Memory, hits: u64) -> bool {
+        self.eval(cpu, memory, hits) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    offset: usize,
+    message: String,
+}
+
+impl ExprError {
+    fn new(offset: usize, message: impl Into<String>) -> Self {
+        Self {
+            offset,
+            message: message.into(),
+        }
+    }
+
+    /// Zero-based byte offset into the source the error was reported at.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.offset + 1, self.message)
+    }
+}
+
+impl error::Error for ExprError {}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(u16),
+    Register(u16),
+    Flag(u16),
+    Hits,
+    Memory(Box<Node>),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnaryOp {
+    Not,
+    Negate,
+    Complement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    BitAnd,
+    Add,
+    Subtract,
+}
+
+struct Context<'a> {
+    cpu: &'a Cpu,
+    memory: &'a Memory,
+    hits: u64,
+}
+
+impl Node {
+    fn eval(&self, context: &Context) -> u16 {
+        match self {
+            Node::Number(value) => *value,
+            Node::Register(index) => context.cpu[*index],
+            Node::Flag(flag) => (context.cpu.condition() & flag != 0) as u16,
+            Node::Hits => context.hits.min(u16::MAX as u64) as u16,
+            Node::Memory(address) => context.memory[address.eval(context)],
+            Node::Unary(op, operand) => {
+                let value = operand.eval(context);
+                match op {
+                    UnaryOp::Not => (value == 0) as u16,
+                    UnaryOp::Negate => value.wrapping_neg(),
+                    UnaryOp::Complement => !value,
+                }
+            }
+            Node::Binary(op, lhs, rhs) => {
+                // the right operand is evaluated only when needed, so `||`
+                // and `&&` short-circuit
+                let a = lhs.eval(context);
+                let b = || rhs.eval(context);
+                match op {
+                    BinaryOp::Or => (a != 0 || b() != 0) as u16,
+                    BinaryOp::And => (a != 0 && b() != 0) as u16,
+                    BinaryOp::Equal => (a == b()) as u16,
+                    BinaryOp::NotEqual => (a != b()) as u16,
+                    BinaryOp::Less => ((a as i16) < (b() as i16)) as u16,
+                    BinaryOp::LessEqual => ((a as i16) <= (b() as i16)) as u16,
+                    BinaryOp::Greater => ((a as i16) > (b() as i16)) as u16,
+                    BinaryOp::GreaterEqual => ((a as i16) >= (b() as i16)) as u16,
+                    BinaryOp::BitAnd => a & b(),
+                    BinaryOp::Add => a.wrapping_add(b()),
+                    BinaryOp::Subtract => a.wrapping_sub(b()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    Number(u16),
+    Name(String),
+    Symbol(&'static str),
+}
+
+struct Token {
+    kind: TokenKind,
+    offset: usize,
+}
+
+/// Longest operators first so that `&&` is not read as two `&`.
+const SYMBOLS: [&str; 17] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "&", "+", "-", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+    while offset < source.len() {
+        let rest = &source[offset..];
+        let ch = rest.chars().next().unwrap_or_default();
+        if ch.is_whitespace() {
+            offset += ch.len_utf8();
+            continue;
+        }
+
+        if ch == '#' || ch.is_ascii_alphanumeric() || ch == '_' {
+            // `#` only introduces decimal literals, which may be negative
+            let len = rest
+                .char_indices()
+                .skip(1)
+                .find(|&(i, ch)| {
+                    !(ch.is_ascii_alphanumeric()
+                        || ch == '_'
+                        || (i == 1 && ch == '-' && rest.starts_with('#')))
+                })
+                .map_or(rest.len(), |(i, _)| i);
+            let word = &rest[..len];
+            let kind = match asm::parse_number(word) {
+                Some(_) => match asm::parse_word(word) {
+                    Some(value) => TokenKind::Number(value),
+                    None => {
+                        return Err(ExprError::new(offset, format!("invalid number: {}", word)))
+                    }
+                },
+                None => TokenKind::Name(word.to_string()),
+            };
+            tokens.push(Token { kind, offset });
+            offset += len;
+            continue;
+        }
+
+        match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            Some(symbol) => {
+                tokens.push(Token {
+                    kind: TokenKind::Symbol(symbol),
+                    offset,
+                });
+                offset += symbol.len();
+            }
+            None => {
+                return Err(ExprError::new(
+                    offset,
+                    format!("unexpected character '{}'", ch),
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    next: usize,
+    end: usize,
+    symbols: &'a BTreeMap<String, u16>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.next).map(|token| &token.kind)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.end, |token| token.offset)
+    }
+
+    /// Consumes the next token if it is one of the symbols in `ops`,
+    /// returning what that symbol stands for.
+    fn eat<T: Copy>(&mut self, ops: &[(&str, T)]) -> Option<T> {
+        let op = match self.peek() {
+            Some(TokenKind::Symbol(symbol)) => {
+                ops.iter().find(|(op, _)| op == symbol).map(|&(_, op)| op)?
+            }
+            _ => return None,
+        };
+        self.next += 1;
+        Some(op)
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), ExprError> {
+        match self.eat(&[(symbol, ())]) {
+            Some(()) => Ok(()),
+            None => Err(ExprError::new(
+                self.offset(),
+                format!("expected '{}'", symbol),
+            )),
+        }
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[(&str, BinaryOp)],
+        operand: fn(&mut Self) -> Result<Node, ExprError>,
+    ) -> Result<Node, ExprError> {
+        let mut lhs = operand(self)?;
+        while let Some(op) = self.eat(ops) {
+            let rhs = operand(self)?;
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn or(&mut self) -> Result<Node, ExprError> {
+        self.binary(&[("||", BinaryOp::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Node, ExprError> {
+        self.binary(&[("&&", BinaryOp::And)], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Node, ExprError> {
+        let lhs = self.bitand()?;
+        let ops = [
+            ("==", BinaryOp::Equal),
+            ("!=", BinaryOp::NotEqual),
+            ("<=", BinaryOp::LessEqual),
+            (">=", BinaryOp::GreaterEqual),
+            ("<", BinaryOp::Less),
+            (">", BinaryOp::Greater),
+        ];
+        match self.eat(&ops) {
+            Some(op) => Ok(Node::Binary(op, Box::new(lhs), Box::new(self.bitand()?))),
+            None => Ok(lhs),
+        }
+    }
+
+    fn bitand(&mut self) -> Result<Node, ExprError> {
+        self.binary(&[("&", BinaryOp::BitAnd)], Self::sum)
+    }
+
+    fn sum(&mut self) -> Result<Node, ExprError> {
+        self.binary(
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Subtract)],
+            Self::unary,
+        )
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprError> {
+        let ops = [
+            ("!", UnaryOp::Not),
+            ("-", UnaryOp::Negate),
+            ("~", UnaryOp::Complement),
+        ];
+        match self.eat(&ops) {
+            Some(op) => Ok(Node::Unary(op, Box::new(self.unary()?))),
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, ExprError> {
+        let offset = self.offset();
+        let token = self.tokens.get(self.next).map(|token| token.kind.clone());
+        self.next += 1;
+        match token {
+            Some(TokenKind::Number(value)) => Ok(Node::Number(value)),
+            Some(TokenKind::Symbol("(")) => {
+                let node = self.or()?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Some(TokenKind::Name(name)) => self.name(&name, offset),
+            _ => Err(ExprError::new(offset, "expected a value")),
+        }
+    }
+
+    fn name(&mut self, name: &str, offset: usize) -> Result<Node, ExprError> {
+        let register = match name.to_ascii_uppercase().as_str() {
+            "R0" => Some(Register::R0),
+            "R1" => Some(Register::R1),
+            "R2" => Some(Register::R2),
+            "R3" => Some(Register::R3),
+            "R4" => Some(Register::R4),
+            "R5" => Some(Register::R5),
+            "R6" => Some(Register::R6),
+            "R7" => Some(Register::R7),
+            "PC" => Some(Register::PC),
+            "PSR" => Some(Register::PSR),
+            "N" => return Ok(Node::Flag(ConditionFlag::NEG.into())),
+            "Z" => return Ok(Node::Flag(ConditionFlag::ZRO.into())),
+            "P" => return Ok(Node::Flag(ConditionFlag::POS.into())),
+            "HITS" => return Ok(Node::Hits),
+            "MEM" => {
+                self.expect("[")?;
+                let address = self.or()?;
+                self.expect("]")?;
+                return Ok(Node::Memory(Box::new(address)));
+            }
+            _ => None,
+        };
+        if let Some(register) = register {
+            return Ok(Node::Register(register as u16));
+        }
+        match self.symbols.get(name) {
+            Some(&address) => Ok(Node::Number(address)),
+            None => Err(ExprError::new(offset, format!("unknown name: {}", name))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+
+    fn eval(source: &str, hits: u64) -> u16 {
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        cpu[Register::R3] = 0x0A;
+        cpu[Register::R5] = 0xFFFE;
+        let mut memory = Memory::new();
+        memory[0x4000] = 0x8000;
+        memory[0x4001] = 7;
+
+        let symbols = BTreeMap::from([("DATA".to_string(), 0x4000)]);
+        Expr::parse(source, &symbols)
+            .unwrap()
+            .eval(&cpu, &memory, hits)
+    }
+
+    fn parse_err(source: &str) -> String {
+        Expr::parse(source, &BTreeMap::new())
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn registers_memory_and_flags_are_operands() {
+        assert_eq!(eval("R3 == x0A && mem[x4000] < 0", 0), 1);
+        assert_eq!(eval("r3 + #-11", 0), 0xFFFF);
+        assert_eq!(eval("mem[DATA + 1]", 0), 7);
+        assert_eq!(eval("Z && !N && !P", 0), 1);
+        assert_eq!(eval("PC == 0x3000", 0), 1);
+        assert_eq!(eval("hits >= 3", 2), 0);
+        assert_eq!(eval("hits >= 3", 3), 1);
+    }
+
+    #[test]
+    fn comparisons_are_signed_and_bind_looser_than_arithmetic() {
+        assert_eq!(eval("R5 < 0", 0), 1);
+        assert_eq!(eval("R5 > x7FFF", 0), 0);
+        assert_eq!(eval("-R5 == 2", 0), 1);
+        assert_eq!(eval("R3 & 2 == 2", 0), 1);
+        assert_eq!(eval("~0 == xFFFF", 0), 1);
+        assert_eq!(eval("0 || R3 == 10 && (R5 != 0)", 0), 1);
+        assert_eq!(eval("(R3 - 1) - 1 == 8", 0), 1);
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_problem() {
+        assert_eq!(parse_err("R3 == "), "column 7: expected a value");
+        assert_eq!(parse_err("mem[x4000"), "column 10: expected ']'");
+        assert_eq!(parse_err("R9 > 1"), "column 1: unknown name: R9");
+        assert_eq!(parse_err("R1 = 2"), "column 4: unexpected character '='");
+        assert_eq!(parse_err("R1 2"), "column 4: expected an operator");
+        assert_eq!(parse_err("x10000"), "column 1: invalid number: x10000");
+    }
+}
@@ -0,0 +1,619 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, Read, Write},
+    ops::RangeInclusive,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use crate::{
+    cpu::{ExitReason, Register, WatchKind},
+    debugger::{Debugger, Stop},
+    error::VmError,
+    memory::LC3_MEMORY_SIZE,
+};
+
+/// The `g` and `G` packets carry R0-R7, PC and PSR, which come first in
+/// the `Cpu` register file.
+const REGISTER_COUNT: u16 = Register::PSR as u16 + 1;
+
+/// Instructions a continued program runs between checks for an interrupt
+/// request from the client.
+const INTERRUPT_CHECK_INTERVAL: u32 = 1024;
+
+/// The byte a client sends to stop a running program.
+const INTERRUPT: u8 = 0x03;
+
+const SUPPORTED: &str = "PacketSize=1000;QStartNoAckMode+;ReverseStep+;ReverseContinue+";
+
+/// Serves the GDB remote serial protocol for a program running under a
+/// `Debugger`, so remote-debug clients can attach to the VM.
+///
+/// The target is big-endian, like the .obj format: the registers R0-R7, PC
+/// and PSR and every memory word are sent as two big-endian bytes. Addresses
+/// are LC-3 word addresses, as everywhere else in the VM: memory packets
+/// count bytes, so `m3000,4` reads the words at x3000 and x3001. Memory is accessed directly,
+/// so device registers have no side effects. Software and hardware
+/// breakpoints are the same thing here, and a watchpoint of `len` bytes
+/// covers `len / 2` words. The reverse execution packets `bs` and `bc` use
+/// the debugger's history. Sending 0x03 while the program runs stops it with
+/// SIGINT.
+pub struct GdbServer {
+    debugger: Debugger,
+    last_stop: String,
+    no_ack: bool,
+    detached: bool,
+}
+
+impl GdbServer {
+    pub fn new(debugger: Debugger) -> Self {
+        Self {
+            debugger,
+            last_stop: "S05".to_string(),
+            no_ack: false,
+            detached: false,
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    /// Answers packets from `input` on `output` until the client kills or
+    /// detaches from the program or closes the connection.
+    pub fn serve(
+        &mut self,
+        input: impl Read + Send + 'static,
+        mut output: impl Write,
+    ) -> io::Result<()> {
+        let mut input = Input::new(input);
+        let mut last_reply = String::new();
+        loop {
+            let packet = match receive(&mut input)? {
+                None => return Ok(()),
+                Some(Incoming::Nak) => {
+                    send(&mut output, &last_reply)?;
+                    continue;
+                }
+                Some(Incoming::Corrupt) => {
+                    if !self.no_ack {
+                        output.write_all(b"-")?;
+                        output.flush()?;
+                    }
+                    continue;
+                }
+                Some(Incoming::Packet(packet)) => packet,
+            };
+
+            if !self.no_ack {
+                output.write_all(b"+")?;
+            }
+            let reply = match self.handle(&packet, &mut input) {
+                Some(reply) => reply,
+                None => return output.flush(),
+            };
+            send(&mut output, &reply)?;
+            if self.detached {
+                return Ok(());
+            }
+            last_reply = reply;
+        }
+    }
+
+    /// Returns the reply to `packet`, or `None` when the client kills the
+    /// program and expects none.
+    fn handle(&mut self, packet: &str, input: &mut Input) -> Option<String> {
+        let reply = match packet {
+            "bs" => {
+                let stop = self.debugger.reverse_step();
+                self.stop(Ok(stop))
+            }
+            "bc" => {
+                let stop = self.debugger.reverse_continue();
+                self.stop(Ok(stop))
+            }
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ if packet.starts_with("qSupported") => SUPPORTED.to_string(),
+            _ => {
+                let (command, args) = packet.split_at(packet.len().min(1));
+                match command {
+                    "?" => self.last_stop.clone(),
+                    "g" => self.registers(),
+                    "G" => ok_or_error(self.set_registers(args)),
+                    "p" => or_error(self.register(args)),
+                    "P" => ok_or_error(self.set_register(args)),
+                    "m" => or_error(self.read_memory(args)),
+                    "M" => ok_or_error(self.write_memory(args)),
+                    "s" | "c" => match self.resume_at(args) {
+                        Some(()) if command == "s" => {
+                            let stop = self.debugger.step();
+                            self.stop(stop)
+                        }
+                        Some(()) => {
+                            let mut steps = 0u32;
+                            let stop = self.debugger.resume_until(|| {
+                                steps = steps.wrapping_add(1);
+                                steps.is_multiple_of(INTERRUPT_CHECK_INTERVAL)
+                                    && input.interrupted()
+                            });
+                            self.stop(stop)
+                        }
+                        None => error(),
+                    },
+                    "Z" => ok_or_error(self.change_point(args, true)),
+                    "z" => ok_or_error(self.change_point(args, false)),
+                    "H" => "OK".to_string(),
+                    "D" => {
+                        self.detached = true;
+                        "OK".to_string()
+                    }
+                    "k" => return None,
+                    _ => String::new(),
+                }
+            }
+        };
+        Some(reply)
+    }
+
+    fn registers(&self) -> String {
+        let cpu = self.debugger.cpu();
+        (0..REGISTER_COUNT)
+            .map(|register| encode_word(cpu[register]))
+            .collect()
+    }
+
+    fn set_registers(&mut self, data: &str) -> Option<()> {
+        if data.len() != REGISTER_COUNT as usize * 4 {
+            return None;
+        }
+        let values = data
+            .as_bytes()
+            .chunks(4)
+            .map(|chunk| decode_word(std::str::from_utf8(chunk).ok()?))
+            .collect::<Option<Vec<u16>>>()?;
+        let cpu = self.debugger.cpu_mut();
+        for (register, value) in (0..REGISTER_COUNT).zip(values) {
+            cpu[register] = value;
+        }
+        Some(())
+    }
+
+    fn register(&self, number: &str) -> Option<String> {
+        let register = register_number(number)?;
+        Some(encode_word(self.debugger.cpu()[register]))
+    }
+
+    fn set_register(&mut self, assignment: &str) -> Option<()> {
+        let (number, value) = assignment.split_once('=')?;
+        let register = register_number(number)?;
+        self.debugger.cpu_mut()[register] = decode_word(value)?;
+        Some(())
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, len) = args.split_once(',')?;
+        let words = words(address, len)?;
+        let memory = self.debugger.memory();
+        Some(words.map(|address| encode_word(memory[address])).collect())
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<()> {
+        let (location, data) = args.split_once(':')?;
+        let (address, len) = location.split_once(',')?;
+        let words = words(address, len)?;
+        if data.len() != words.len() * 4 {
+            return None;
+        }
+        let values = data
+            .as_bytes()
+            .chunks(4)
+            .map(|chunk| decode_word(std::str::from_utf8(chunk).ok()?))
+            .collect::<Option<Vec<u16>>>()?;
+        let memory = self.debugger.memory_mut();
+        for (address, value) in words.zip(values) {
+            memory[address] = value;
+        }
+        Some(())
+    }
+
+    /// Moves PC to the address a step or continue packet resumes at, if it
+    /// gives one.
+    fn resume_at(&mut self, address: &str) -> Option<()> {
+        if !address.is_empty() {
+            self.debugger.cpu_mut()[Register::PC] = u16::from_str_radix(address, 16).ok()?;
+        }
+        Some(())
+    }
+
+    /// Handles `Z` and `z` packets, which insert and remove breakpoints and
+    /// watchpoints.
+    fn change_point(&mut self, args: &str, insert: bool) -> Option<()> {
+        let args = args.split(';').next()?;
+        let mut fields = args.split(',');
+        let (kind, address, len, None) = (
+            fields.next()?,
+            fields.next()?,
+            fields.next()?,
+            fields.next(),
+        ) else {
+            return None;
+        };
+        let address = u16::from_str_radix(address, 16).ok()?;
+        let watch = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.debugger.add_breakpoint(address);
+                } else {
+                    self.debugger.remove_breakpoint(address);
+                }
+                return Some(());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return None,
+        };
+
+        let words = (usize::from_str_radix(len, 16).ok()? / 2).max(1);
+        if words > LC3_MEMORY_SIZE {
+            return None;
+        }
+        let last = address.checked_add((words - 1) as u16)?;
+        let cpu = self.debugger.cpu_mut();
+        if insert {
+            cpu.add_watchpoint(address..=last, watch);
+        } else {
+            cpu.remove_watchpoint(&(address..=last), watch);
+        }
+        Some(())
+    }
+
+    /// Builds the stop reply for `stop` and remembers it for `?`.
+    fn stop(&mut self, stop: Result<Stop, VmError>) -> String {
+        let reply = match stop {
+            Ok(Stop::Step) | Ok(Stop::Breakpoint(_)) => "S05".to_string(),
+            Ok(Stop::Watchpoint(hit)) => {
+                let accessed = self.debugger.cpu().watchpoints().iter().any(|watchpoint| {
+                    watchpoint.kind == WatchKind::Access && watchpoint.range.contains(&hit.address)
+                });
+                let reason = match hit.access {
+                    _ if accessed => "awatch",
+                    WatchKind::Read => "rwatch",
+                    _ => "watch",
+                };
+                format!("T05{}:{:04x};", reason, hit.address)
+            }
+            Ok(Stop::HistoryStart) => "T05replaylog:begin;".to_string(),
+            Ok(Stop::Interrupted) => "S02".to_string(),
+            // the operating system stopped the machine after a fault
+            Ok(Stop::Exited(ExitReason::Faulted)) => "X06".to_string(),
+            Ok(Stop::Exited(_)) => format!("W{:02x}", self.debugger.cpu()[Register::R0] & 0xFF),
+            // SIGILL for instructions the machine refuses, SIGSEGV for access
+            // violations and SIGABRT for everything else
+            Err(VmError::IllegalOpcode { .. } | VmError::PrivilegeViolation { .. }) => {
+                "S04".to_string()
+            }
+            Err(VmError::AccessViolation { .. }) => "S0b".to_string(),
+            Err(_) => "S06".to_string(),
+        };
+        self.last_stop = reply.clone();
+        reply
+    }
+}
+
+/// Bytes from the client, read on a background thread so that a running
+/// program can watch for interrupt requests without blocking.
+struct Input {
+    bytes: Receiver<io::Result<u8>>,
+    /// Bytes that arrived while the program ran, other than interrupts.
+    pending: VecDeque<io::Result<u8>>,
+}
+
+impl Input {
+    fn new(input: impl Read + Send + 'static) -> Self {
+        let (sender, bytes) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in BufReader::new(input).bytes() {
+                let failed = byte.is_err();
+                if sender.send(byte).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Self {
+            bytes,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Whether an interrupt request arrived since the last check, keeping
+    /// any other bytes for `next`.
+    fn interrupted(&mut self) -> bool {
+        while let Ok(byte) = self.bytes.try_recv() {
+            if matches!(byte, Ok(INTERRUPT)) {
+                return true;
+            }
+            self.pending.push_back(byte);
+        }
+        false
+    }
+}
+
+impl Iterator for Input {
+    type Item = io::Result<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pending.pop_front().or_else(|| self.bytes.recv().ok())
+    }
+}
+
+enum Incoming {
+    Packet(String),
+    /// A packet whose checksum did not match.
+    Corrupt,
+    /// The client asked for the last reply again.
+    Nak,
+}
+
+fn receive(input: &mut impl Iterator<Item = io::Result<u8>>) -> io::Result<Option<Incoming>> {
+    loop {
+        match input.next().transpose()? {
+            None => return Ok(None),
+            Some(b'$') => break,
+            Some(b'-') => return Ok(Some(Incoming::Nak)),
+            // acknowledgements, and interrupt requests while nothing runs
+            Some(_) => {}
+        }
+    }
+
+    let mut data = Vec::new();
+    loop {
+        match input.next().transpose()? {
+            None => return Ok(None),
+            Some(b'#') => break,
+            Some(byte) => data.push(byte),
+        }
+    }
+    let mut digits = [0; 2];
+    for digit in &mut digits {
+        match input.next().transpose()? {
+            None => return Ok(None),
+            Some(byte) => *digit = byte,
+        }
+    }
+
+    let expected = std::str::from_utf8(&digits)
+        .ok()
+        .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+    if expected != Some(checksum(&data)) {
+        return Ok(Some(Incoming::Corrupt));
+    }
+    Ok(Some(Incoming::Packet(
+        String::from_utf8_lossy(&data).into_owned(),
+    )))
+}
+
+fn send(output: &mut impl Write, reply: &str) -> io::Result<()> {
+    write!(output, "${}#{:02x}", reply, checksum(reply.as_bytes()))?;
+    output.flush()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn encode_word(value: u16) -> String {
+    format!("{:04x}", value)
+}
+
+fn register_number(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16)
+        .ok()
+        .filter(|&number| number < REGISTER_COUNT)
+}
+
+fn decode_word(text: &str) -> Option<u16> {
+    if text.len() != 4 || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    u16::from_str_radix(text, 16).ok()
+}
+
+/// The words covered by a memory packet's address and byte length.
+fn words(address: &str, len: &str) -> Option<RangeInclusive<u16>> {
+    let address = u16::from_str_radix(address, 16).ok()?;
+    let len = u16::from_str_radix(len, 16).ok()?;
+    if len == 0 || len % 2 != 0 {
+        return None;
+    }
+    Some(address..=address.checked_add(len / 2 - 1)?)
+}
+
+fn ok_or_error(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_string(),
+        None => error(),
+    }
+}
+
+fn or_error(reply: Option<String>) -> String {
+    reply.unwrap_or_else(error)
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, console::BufferConsole, cpu::Cpu, memory::Memory};
+
+    const PROGRAM: &str = "
+        .ORIG x3000
+        AND R1, R1, #0
+LOOP    ADD R1, R1, #1
+        ST R1, COUNT
+        ADD R2, R1, #-3
+        BRn LOOP
+        AND R0, R0, #0
+        ADD R0, R0, #7
+        HALT
+COUNT   .FILL #0
+        .END";
+
+    fn server() -> GdbServer {
+        let mut memory = Memory::new();
+        assemble(PROGRAM).unwrap().image().load_into(&mut memory);
+        let cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        GdbServer::new(Debugger::new(cpu, memory))
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum(data.as_bytes()))
+    }
+
+    /// Sends `packets` and returns the replies without acknowledgements.
+    fn exchange(server: &mut GdbServer, packets: &[&str]) -> Vec<String> {
+        let input: String = packets.iter().map(|data| packet(data)).collect();
+        let mut output = Vec::new();
+        server.serve(io::Cursor::new(input), &mut output).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .split('$')
+            .skip(1)
+            .map(|reply| reply.split('#').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn registers_and_memory_can_be_read_and_written() {
+        let mut server = server();
+        let replies = exchange(
+            &mut server,
+            &[
+                "qSupported:multiprocess+;swbreak+",
+                "g",
+                "P1=1234",
+                "p1",
+                "pa",
+                "m3000,4",
+                "M4000,4:abcd0102",
+                "m4000,4",
+                "m3000,3",
+                "Z2,0,20002",
+                "Z2,fff0,40",
+                "vMustReplyEmpty",
+            ],
+        );
+
+        assert_eq!(
+            replies,
+            [
+                SUPPORTED,
+                "0000000000000000000000000000000030000002",
+                "OK",
+                "1234",
+                "E01",
+                "52601261",
+                "OK",
+                "abcd0102",
+                "E01",
+                "E01",
+                "E01",
+                "",
+            ]
+            .map(String::from)
+        );
+        assert_eq!(server.debugger().cpu()[Register::R1], 0x1234);
+        assert_eq!(server.debugger().memory()[0x4001], 0x0102);
+        assert!(server.debugger().cpu().watchpoints().is_empty());
+    }
+
+    #[test]
+    fn breakpoints_watchpoints_and_reverse_execution_stop_the_program() {
+        let mut server = server();
+        let replies = exchange(
+            &mut server,
+            &[
+                "Z0,3003,2",
+                "c",
+                "p8",
+                "z0,3003,2",
+                "Z2,3008,2",
+                "c",
+                "bs",
+                "p8",
+                "z2,3008,2",
+                "s",
+                "c",
+                "?",
+                "bc",
+            ],
+        );
+
+        assert_eq!(
+            replies,
+            [
+                "OK",
+                "S05",
+                "3003",
+                "OK",
+                "OK",
+                "T05watch:3008;",
+                "S05",
+                "3002",
+                "OK",
+                "S05",
+                "W07",
+                "W07",
+                "T05replaylog:begin;",
+            ]
+            .map(String::from)
+        );
+        assert_eq!(server.debugger().cpu()[Register::PC], 0x3000);
+    }
+
+    #[test]
+    fn interrupt_stops_a_running_program() {
+        let mut memory = Memory::new();
+        assemble(".ORIG x3000\nLOOP BRnzp LOOP\n.END")
+            .unwrap()
+            .image()
+            .load_into(&mut memory);
+        let cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        let mut server = GdbServer::new(Debugger::new(cpu, memory));
+        let input = format!("{}\x03{}{}", packet("c"), packet("?"), packet("p8"));
+        let mut output = Vec::new();
+        server.serve(io::Cursor::new(input), &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("+{}+{}+{}", packet("S02"), packet("S02"), packet("3000"))
+        );
+    }
+
+    #[test]
+    fn corrupt_packets_are_refused_and_kill_ends_the_session() {
+        let mut server = server();
+        let input = format!("+$g#00{}-{}{}", packet("s"), packet("k"), packet("g"));
+        let mut output = Vec::new();
+        server.serve(io::Cursor::new(input), &mut output).unwrap();
+
+        let stop = packet("S05");
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("-+{}{}+", stop, stop)
+        );
+        assert_eq!(
+            server.debugger.resume().unwrap(),
+            Stop::Exited(ExitReason::Halted)
+        );
+    }
+}
@@ -1,2 +1,11 @@
+pub mod asm;
+pub mod console;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod expr;
+pub mod gdb;
+pub mod loader;
 pub mod memory;
+pub mod os;
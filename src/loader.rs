@@ -0,0 +1,343 @@
+use std::{collections::BTreeMap, error, fmt, fs, io, ops::Range, path::Path};
+
+use crate::memory::{Memory, LC3_MEMORY_SIZE};
+
+pub struct ObjectImage {
+    origin: u16,
+    words: Vec<u16>,
+}
+
+impl ObjectImage {
+    /// Fails if `words` placed at `origin` would run past the end of memory.
+    pub fn new(origin: u16, words: Vec<u16>) -> Result<Self, LoadError> {
+        if origin as usize + words.len() > LC3_MEMORY_SIZE {
+            return Err(LoadError::TooLarge {
+                origin,
+                len: words.len(),
+            });
+        }
+        Ok(Self { origin, words })
+    }
+
+    /// Parses the big-endian .obj format: the first word is the origin and
+    /// every following word is placed consecutively starting there.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        if bytes.len() < 2 || !bytes.len().is_multiple_of(2) {
+            return Err(LoadError::Truncated { len: bytes.len() });
+        }
+
+        let mut words = bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        let origin = words.next().unwrap_or_default();
+        Self::new(origin, words.collect())
+    }
+
+    pub fn origin(&self) -> u16 {
+        self.origin
+    }
+
+    pub fn words(&self) -> &[u16] {
+        &self.words
+    }
+
+    /// Serializes the image back into the big-endian .obj format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        std::iter::once(self.origin)
+            .chain(self.words.iter().copied())
+            .flat_map(u16::to_be_bytes)
+            .collect()
+    }
+
+    pub fn load_into(&self, memory: &mut Memory) {
+        memory.write_at(&self.words, self.origin as usize);
+    }
+
+    fn start(&self) -> usize {
+        self.origin as usize
+    }
+
+    fn end(&self) -> usize {
+        self.start() + self.words.len()
+    }
+}
+
+/// Several object images sharing one memory, such as a program together
+/// with the data and subroutine files it uses. Images are kept in the order
+/// they were added and may not overlap.
+#[derive(Default)]
+pub struct ImageSet {
+    images: Vec<(String, ObjectImage)>,
+    reserved: Vec<(String, Range<usize>)>,
+}
+
+impl ImageSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks `addresses`, which are filled some other way, as taken, so
+    /// images added later may not overlap them.
+    pub fn reserve(&mut self, name: impl Into<String>, addresses: Range<usize>) {
+        self.reserved.push((name.into(), addresses));
+    }
+
+    /// Adds `image` under `name`, which identifies it in overlap errors.
+    pub fn add(&mut self, name: impl Into<String>, image: ObjectImage) -> Result<(), LoadError> {
+        let name = name.into();
+        let taken = self.reserved.iter().cloned().chain(
+            self.images
+                .iter()
+                .map(|(name, image)| (name.clone(), image.start()..image.end())),
+        );
+        for (other_name, other) in taken {
+            let start = image.start().max(other.start);
+            let end = image.end().min(other.end);
+            if start < end {
+                return Err(LoadError::Overlap {
+                    first: other_name,
+                    second: name,
+                    start: start as u16,
+                    end: (end - 1) as u16,
+                });
+            }
+        }
+        self.images.push((name, image));
+        Ok(())
+    }
+
+    pub fn images(&self) -> impl Iterator<Item = (&str, &ObjectImage)> {
+        self.images
+            .iter()
+            .map(|(name, image)| (name.as_str(), image))
+    }
+
+    /// Entry point by convention: the origin of the first image.
+    pub fn entry(&self) -> Option<u16> {
+        self.images.first().map(|(_, image)| image.origin())
+    }
+
+    pub fn load_into(&self, memory: &mut Memory) {
+        for (_, image) in &self.images {
+            image.load_into(memory);
+        }
+    }
+}
+
+pub fn load_file<P: AsRef<Path>>(path: P) -> Result<ObjectImage, LoadError> {
+    let bytes = fs::read(path).map_err(LoadError::Io)?;
+    ObjectImage::from_bytes(&bytes)
+}
+
+/// Parses a symbol table in the `.sym` format written by `lc3as`, where each
+/// symbol sits on a comment line followed by its hexadecimal address:
+///
+/// ```text
+/// //    Symbol Name       Page Address
+/// //    ----------------  ------------
+/// //    LOOP              3003
+/// ```
+pub fn parse_symbol_table(text: &str) -> BTreeMap<String, u16> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.trim_start().strip_prefix("//").unwrap_or(line);
+            let mut fields = line.split_whitespace();
+            let (name, address, None) = (fields.next()?, fields.next()?, fields.next()) else {
+                return None;
+            };
+            let address = u16::from_str_radix(address, 16).ok()?;
+            Some((name.to_string(), address))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Truncated {
+        len: usize,
+    },
+    TooLarge {
+        origin: u16,
+        len: usize,
+    },
+    /// Two images claim the addresses `start..=end`.
+    Overlap {
+        first: String,
+        second: String,
+        start: u16,
+        end: u16,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "could not read object file: {}", err),
+            LoadError::Truncated { len } => {
+                write!(
+                    f,
+                    "truncated object file: {} bytes is not a whole word image",
+                    len
+                )
+            }
+            LoadError::TooLarge { origin, len } => write!(
+                f,
+                "object file too large: {} words at origin {:#06x} run past the end of memory",
+                len, origin
+            ),
+            LoadError::Overlap {
+                first,
+                second,
+                start,
+                end,
+            } => write!(
+                f,
+                "{} overlaps {} at {:#06x}..={:#06x}",
+                second, first, start, end
+            ),
+        }
+    }
+}
+
+impl error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LoadError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_bytes_reads_big_endian_origin_and_words() {
+        let image = ObjectImage::from_bytes(&[0x30, 0x00, 0x12, 0x34, 0xF0, 0x25]).unwrap();
+        assert_eq!(image.origin(), 0x3000);
+        assert_eq!(image.words(), [0x1234, 0xF025]);
+        assert_eq!(image.to_bytes(), [0x30, 0x00, 0x12, 0x34, 0xF0, 0x25]);
+    }
+
+    #[test]
+    fn from_bytes_rejects_partial_words_and_missing_origin() {
+        for bytes in [&[][..], &[0x30], &[0x30, 0x00, 0x12]] {
+            assert!(matches!(
+                ObjectImage::from_bytes(bytes),
+                Err(LoadError::Truncated { len }) if len == bytes.len()
+            ));
+        }
+    }
+
+    #[test]
+    fn from_bytes_rejects_images_running_past_the_end_of_memory() {
+        let fits = ObjectImage::from_bytes(&[0xFF, 0xFF, 0x00, 0x01]).unwrap();
+        assert_eq!(fits.words(), [1]);
+
+        let err = ObjectImage::from_bytes(&[0xFF, 0xFF, 0x00, 0x01, 0x00, 0x02])
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            LoadError::TooLarge {
+                origin: 0xFFFF,
+                len: 2
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "object file too large: 2 words at origin 0xffff run past the end of memory"
+        );
+    }
+
+    #[test]
+    fn new_rejects_images_running_past_the_end_of_memory() {
+        let image = ObjectImage::new(0xFFFF, vec![7]).unwrap();
+        let mut memory = Memory::new();
+        image.load_into(&mut memory);
+        assert_eq!(memory[0xFFFF], 7);
+
+        assert!(matches!(
+            ObjectImage::new(0xFFFF, vec![0; 2]),
+            Err(LoadError::TooLarge {
+                origin: 0xFFFF,
+                len: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn image_set_loads_every_image_and_enters_at_the_first() {
+        let mut set = ImageSet::new();
+        set.add("main.obj", ObjectImage::new(0x3000, vec![1, 2]).unwrap())
+            .unwrap();
+        set.add("data.obj", ObjectImage::new(0x4000, vec![3]).unwrap())
+            .unwrap();
+        set.add("next.obj", ObjectImage::new(0x3002, vec![4]).unwrap())
+            .unwrap();
+
+        let mut memory = Memory::new();
+        set.load_into(&mut memory);
+        assert_eq!(set.entry(), Some(0x3000));
+        assert_eq!(
+            [
+                memory[0x3000],
+                memory[0x3001],
+                memory[0x3002],
+                memory[0x4000]
+            ],
+            [1, 2, 4, 3]
+        );
+    }
+
+    #[test]
+    fn image_set_rejects_overlapping_images() {
+        let mut set = ImageSet::new();
+        set.add("main.obj", ObjectImage::new(0x3000, vec![0; 0x10]).unwrap())
+            .unwrap();
+        let err = set
+            .add("sub.obj", ObjectImage::new(0x300C, vec![0; 0x10]).unwrap())
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "sub.obj overlaps main.obj at 0x300c..=0x300f"
+        );
+        assert_eq!(set.images().count(), 1);
+    }
+
+    #[test]
+    fn image_set_rejects_images_over_reserved_regions() {
+        let mut set = ImageSet::new();
+        set.reserve("the OS", 0x0000..0x0300);
+        let err = set
+            .add("low.obj", ObjectImage::new(0x02FE, vec![0; 4]).unwrap())
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "low.obj overlaps the OS at 0x02fe..=0x02ff"
+        );
+        set.add("main.obj", ObjectImage::new(0x3000, vec![1]).unwrap())
+            .unwrap();
+        assert_eq!(set.entry(), Some(0x3000));
+    }
+
+    #[test]
+    fn symbol_tables_from_lc3as_are_parsed() {
+        let symbols = parse_symbol_table(
+            "// Symbol table
+// Scope level 0:
+//\tSymbol Name       Page Address
+//\t----------------  ------------
+//\tSTART             3000
+//\tLOOP              3003
+",
+        );
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols["START"], 0x3000);
+        assert_eq!(symbols["LOOP"], 0x3003);
+    }
+}
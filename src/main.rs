@@ -1,11 +1,424 @@
-use lc3_vm::{cpu::Cpu, memory::Memory};
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::TcpListener,
+    path::Path,
+    process,
+};
+
+use lc3_vm::{
+    asm,
+    console::{Console, StdinReader, StdioConsole, StreamConsole},
+    cpu::{Cpu, ExitReason, Register},
+    debugger::Debugger,
+    disasm,
+    gdb::GdbServer,
+    loader::{self, ImageSet, ObjectImage},
+    memory::Memory,
+    os,
+};
+
+const USAGE: &str = "usage: lc3-vm run [options] <image.obj>...
+       lc3-vm debug [options] <image.obj>...
+       lc3-vm gdb [--port <n> | --stdio] [options] <image.obj>...
+       lc3-vm disasm <image.obj> [start [end]]
+       lc3-vm <image.obj>
+
+run, debug and gdb options:
+  --pc <address>            start at <address> instead of the first image's origin
+  --max-instructions <n>    stop with exit status 3 after <n> instructions
+  --input <file>            read keyboard input from <file> instead of stdin
+  --output <file>           write display output to <file> instead of stdout
+  --quiet                   do not print the HALT message
+  --native-traps            run traps with the built-in routines instead of the bundled OS
+  --exit-code               exit with the low byte of R0 once the program halts
+
+gdb options:
+  --port <n>                wait for a GDB remote connection on localhost port <n> (1234)
+  --stdio                   speak the GDB remote protocol on stdin and stdout; the
+                            display goes to stderr and keyboard input needs --input
+
+Images are loaded in order and may not overlap each other or, without
+--native-traps, the bundled OS routines at x0200. They may fill in trap and
+interrupt vector entries, replacing the OS defaults. An image may also be
+an .asm source, which is assembled first. The debugger reads labels from the
+.sym file next to each .obj image.";
+
+/// Exit status when `--max-instructions` runs out.
+const INSTRUCTION_LIMIT_STATUS: i32 = 3;
+
+/// Port `gdb` listens on without `--port`, the one gdbserver examples use.
+const DEFAULT_GDB_PORT: u16 = 1234;
 
 fn main() {
-    let instr = [0b1111000000100011, 0b1111000000100101];
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("disasm") => disasm_command(&args[1..]),
+        Some("run") => run_command(&args[1..]),
+        Some("debug") => debug_command(&args[1..]),
+        Some("gdb") => gdb_command(&args[1..]),
+        Some(_) if args.len() == 1 => run_command(&args),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn load(path: &str) -> ObjectImage {
+    match loader::load_file(path) {
+        Ok(image) => image,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct RunOptions {
+    images: Vec<String>,
+    pc: Option<u16>,
+    max_instructions: Option<u64>,
+    input: Option<String>,
+    output: Option<String>,
+    quiet: bool,
+    native_traps: bool,
+    exit_code: bool,
+}
+
+impl RunOptions {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--pc" => {
+                    let text = value()?;
+                    let pc = asm::parse_word(text)
+                        .ok_or_else(|| format!("invalid address: {}", text))?;
+                    options.pc = Some(pc);
+                }
+                "--max-instructions" => {
+                    let text = value()?;
+                    let limit = text
+                        .parse()
+                        .map_err(|_| format!("invalid instruction count: {}", text))?;
+                    options.max_instructions = Some(limit);
+                }
+                "--input" => options.input = Some(value()?.clone()),
+                "--output" => options.output = Some(value()?.clone()),
+                "--quiet" => options.quiet = true,
+                "--native-traps" => options.native_traps = true,
+                "--exit-code" => options.exit_code = true,
+                flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+                path => options.images.push(path.to_string()),
+            }
+        }
+
+        if options.images.is_empty() {
+            return Err("no image given".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn parse_options(args: &[String]) -> RunOptions {
+    RunOptions::parse(args).unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        usage();
+    })
+}
+
+fn open_console(options: &RunOptions) -> io::Result<Box<dyn Console>> {
+    let output = match &options.output {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    Ok(match (&options.input, output) {
+        (Some(path), Some(output)) => Box::new(StreamConsole::new(File::open(path)?, output)),
+        (Some(path), None) => Box::new(StreamConsole::new(File::open(path)?, io::stdout())),
+        (None, Some(output)) => Box::new(StdioConsole::with_output(output)),
+        (None, None) => Box::new(StdioConsole::new()),
+    })
+}
+
+/// Loads an object file together with the labels from a `.sym` file next to
+/// it, or assembles an `.asm` source.
+fn load_with_symbols(path: &str) -> (ObjectImage, BTreeMap<String, u16>) {
+    let path_ref = Path::new(path);
+    if path_ref.extension().is_some_and(|ext| ext == "asm") {
+        let program = fs::read_to_string(path_ref)
+            .map_err(|err| err.to_string())
+            .and_then(|source| asm::assemble(&source).map_err(|err| err.to_string()))
+            .unwrap_or_else(|err| {
+                eprintln!("{}: {}", path, err);
+                process::exit(1);
+            });
+        let symbols = program.symbols().clone();
+        return (program.into_image(), symbols);
+    }
+
+    let symbols = fs::read_to_string(path_ref.with_extension("sym"))
+        .map(|text| loader::parse_symbol_table(&text))
+        .unwrap_or_default();
+    (load(path), symbols)
+}
+
+/// How `gdb` talks to the client.
+#[derive(Debug, PartialEq)]
+enum Transport {
+    Tcp(u16),
+    Stdio,
+}
+
+/// Splits the `gdb` arguments into the transport and the run options.
+fn parse_gdb_args(args: &[String]) -> Result<(Transport, Vec<String>), String> {
+    let mut transport = Transport::Tcp(DEFAULT_GDB_PORT);
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => {
+                let text = args.next().ok_or("--port needs a value")?;
+                let port = text
+                    .parse()
+                    .map_err(|_| format!("invalid port: {}", text))?;
+                transport = Transport::Tcp(port);
+            }
+            "--stdio" => transport = Transport::Stdio,
+            _ => rest.push(arg.clone()),
+        }
+    }
+    Ok((transport, rest))
+}
+
+/// The console for `gdb --stdio`, which keeps stdin and stdout for the
+/// protocol.
+fn open_gdb_console(options: &RunOptions) -> io::Result<Box<dyn Console>> {
+    let input: Box<dyn Read> = match &options.input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::empty()),
+    };
+    let output: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stderr()),
+    };
+    Ok(Box::new(StreamConsole::new(input, output)))
+}
+
+/// Loads the images and prepares the machine as `run` and `debug` start it,
+/// returning the labels the images define.
+fn start(options: &RunOptions) -> (Cpu, Memory, BTreeMap<String, u16>) {
+    start_with_console(options, open_console(options))
+}
+
+fn start_with_console(
+    options: &RunOptions,
+    console: io::Result<Box<dyn Console>>,
+) -> (Cpu, Memory, BTreeMap<String, u16>) {
+    let mut images = ImageSet::new();
+    if !options.native_traps {
+        images.reserve("the bundled operating system", os::routines());
+    }
+    let mut symbols = BTreeMap::new();
+    for path in &options.images {
+        let (image, image_symbols) = load_with_symbols(path);
+        if let Err(err) = images.add(path.as_str(), image) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        symbols.extend(image_symbols);
+    }
+
+    let console = console.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    let mut cpu = Cpu::with_console(console);
+    let mut memory = if options.native_traps {
+        Memory::new()
+    } else {
+        Memory::with_os()
+    };
+
+    images.load_into(&mut memory);
+    let entry = options.pc.or(images.entry()).unwrap_or_default();
+    if options.native_traps {
+        cpu[Register::PC] = entry;
+        if options.quiet {
+            cpu.register_trap(0x25, |_: &mut Cpu, memory: &mut Memory| {
+                memory.set_clock_enabled(false);
+                Ok(())
+            });
+        }
+    } else {
+        os::boot(&mut cpu, entry);
+        if options.quiet {
+            os::quiet_halt(&mut memory);
+        }
+    }
+    (cpu, memory, symbols)
+}
+
+fn run_command(args: &[String]) {
+    let options = parse_options(args);
+    let (mut cpu, mut memory, _) = start(&options);
+
+    let result = match options.max_instructions {
+        Some(limit) => cpu.execute_limited(&mut memory, limit),
+        None => cpu.execute(&mut memory),
+    };
+    let _ = cpu.console_mut().flush();
+    match result {
+        Ok(ExitReason::InstructionLimit) => {
+            eprintln!("{}: instruction limit reached", options.images[0]);
+            process::exit(INSTRUCTION_LIMIT_STATUS);
+        }
+        Ok(ExitReason::Faulted) => {
+            eprintln!("{}: stopped after a fault", options.images[0]);
+            process::exit(1);
+        }
+        Ok(_) if options.exit_code => process::exit((cpu[Register::R0] & 0xFF) as i32),
+        Ok(_) => {}
+        Err(err) => {
+            eprintln!("{}: {}", options.images[0], err);
+            process::exit(1);
+        }
+    }
+}
+
+fn debug_command(args: &[String]) {
+    let options = parse_options(args);
+    let (cpu, memory, symbols) = start(&options);
+
+    let mut debugger = Debugger::new(cpu, memory);
+    debugger.add_symbols(symbols);
+    if let Err(err) = debugger.repl(BufReader::new(StdinReader), io::stdout()) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn gdb_command(args: &[String]) {
+    let (transport, args) = parse_gdb_args(args).unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        usage();
+    });
+    let options = parse_options(&args);
+    let console = match transport {
+        Transport::Tcp(_) => open_console(&options),
+        Transport::Stdio => open_gdb_console(&options),
+    };
+    let (cpu, memory, symbols) = start_with_console(&options, console);
+
+    let mut debugger = Debugger::new(cpu, memory);
+    debugger.add_symbols(symbols);
+    let mut server = GdbServer::new(debugger);
+    let result = match transport {
+        Transport::Tcp(port) => TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+            eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+            let (stream, _) = listener.accept()?;
+            server.serve(stream.try_clone()?, stream)
+        }),
+        Transport::Stdio => server.serve(StdinReader, io::stdout()),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn disasm_command(args: &[String]) {
+    let (path, bounds) = match args.split_first() {
+        Some((path, bounds)) if bounds.len() <= 2 => (path, bounds),
+        _ => usage(),
+    };
+    let image = load(path);
 
-    let mut cpu = Cpu::new();
     let mut memory = Memory::new();
+    image.load_into(&mut memory);
+
+    let address = |index: usize, default: u16| match bounds.get(index) {
+        Some(text) => asm::parse_word(text).unwrap_or_else(|| {
+            eprintln!("invalid address: {}", text);
+            process::exit(2);
+        }),
+        None => default,
+    };
+    let last = image.origin() as usize + image.words().len().max(1) - 1;
+    let start = address(0, image.origin());
+    let end = address(1, last as u16);
+
+    print!("{}", disasm::disasm(&memory, start..=end));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn run_options_parse_flags_and_images() {
+        let options = RunOptions::parse(&args(
+            "--pc x3010 a.obj --max-instructions 500 --quiet b.obj --input in.txt --exit-code",
+        ))
+        .unwrap();
+        assert_eq!(
+            options,
+            RunOptions {
+                images: args("a.obj b.obj"),
+                pc: Some(0x3010),
+                max_instructions: Some(500),
+                input: Some("in.txt".to_string()),
+                quiet: true,
+                exit_code: true,
+                ..RunOptions::default()
+            }
+        );
+    }
+
+    #[test]
+    fn run_options_reject_bad_arguments() {
+        for (text, message) in [
+            ("", "no image given"),
+            ("a.obj --pc", "--pc needs a value"),
+            ("a.obj --pc x1FFFF", "invalid address: x1FFFF"),
+            (
+                "a.obj --max-instructions lots",
+                "invalid instruction count: lots",
+            ),
+            ("a.obj --verbose", "unknown option: --verbose"),
+        ] {
+            assert_eq!(RunOptions::parse(&args(text)).unwrap_err(), message);
+        }
+    }
 
-    memory.write_at(&instr, 0x3000);
-    cpu.execute(&mut memory)
+    #[test]
+    fn gdb_args_choose_the_transport() {
+        assert_eq!(
+            parse_gdb_args(&args("--quiet a.obj")).unwrap(),
+            (Transport::Tcp(DEFAULT_GDB_PORT), args("--quiet a.obj"))
+        );
+        assert_eq!(
+            parse_gdb_args(&args("--port 3333 a.obj --stdio")).unwrap(),
+            (Transport::Stdio, args("a.obj"))
+        );
+        assert_eq!(
+            parse_gdb_args(&args("--port 3333 a.obj")).unwrap().0,
+            Transport::Tcp(3333)
+        );
+        assert_eq!(
+            parse_gdb_args(&args("a.obj --port 99999")).unwrap_err(),
+            "invalid port: 99999"
+        );
+    }
 }
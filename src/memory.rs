@@ -1,18 +1,164 @@
-use std::ops::{Index, IndexMut};
+use std::{
+    io,
+    ops::{Index, IndexMut},
+};
 
-const LC3_MEMORY_SIZE: usize = 1 << 16;
+use crate::{console::Console, os};
+
+/// Number of words in the LC-3 address space.
+pub const LC3_MEMORY_SIZE: usize = 1 << 16;
+
+/// First address available to user-mode programs; everything below is
+/// system space.
+pub const USER_SPACE_START: u16 = 0x3000;
+/// First address of the memory-mapped device register region.
+pub const DEVICE_REGION_START: u16 = 0xFE00;
+/// Keyboard status register: bit 15 is set when KBDR holds a new character.
+pub const KBSR: u16 = 0xFE00;
+/// Keyboard data register.
+pub const KBDR: u16 = 0xFE02;
+/// Display status register: bit 15 is set when the display is ready.
+pub const DSR: u16 = 0xFE04;
+/// Display data register.
+pub const DDR: u16 = 0xFE06;
+/// Machine control register: clearing bit 15 stops the clock. The bundled
+/// operating system sets bit 14 when it stops the machine after a fault.
+pub const MCR: u16 = 0xFFFE;
+
+/// Interrupt vector and priority used by the keyboard.
+pub const KEYBOARD_INTERRUPT_VECTOR: u8 = 0x80;
+pub const KEYBOARD_INTERRUPT_PRIORITY: u8 = 4;
+
+const STATUS_READY: u16 = 1 << 15;
+const KBSR_INTERRUPT_ENABLE: u16 = 1 << 14;
+const MCR_CLOCK_ENABLE: u16 = 1 << 15;
+const MCR_FAULT: u16 = 1 << 14;
 
 pub struct Memory([u16; LC3_MEMORY_SIZE]);
 
 impl Memory {
     pub fn new() -> Self {
-        Self([0; LC3_MEMORY_SIZE])
+        let mut memory = Self([0; LC3_MEMORY_SIZE]);
+        memory[DSR] = STATUS_READY;
+        memory[MCR] = MCR_CLOCK_ENABLE;
+        memory
+    }
+
+    /// Memory with the bundled operating system loaded into system space.
+    pub fn with_os() -> Self {
+        let mut memory = Self::new();
+        os::load_into(&mut memory);
+        memory
+    }
+
+    pub fn clock_enabled(&self) -> bool {
+        self[MCR] & MCR_CLOCK_ENABLE != 0
+    }
+
+    /// Whether the operating system stopped the machine because of an
+    /// exception, an undefined trap or an unexpected interrupt.
+    pub fn faulted(&self) -> bool {
+        self[MCR] & MCR_FAULT != 0
+    }
+
+    pub fn set_clock_enabled(&mut self, enabled: bool) {
+        if enabled {
+            self[MCR] |= MCR_CLOCK_ENABLE;
+        } else {
+            self[MCR] &= !MCR_CLOCK_ENABLE;
+        }
     }
 
     pub fn write_at(&mut self, values: &[u16], offset: usize) {
         let slice = &mut self.0[offset..offset + values.len()];
         slice.copy_from_slice(values);
     }
+
+    /// Reads a word as the CPU sees it, routing device registers to their
+    /// handlers. Plain indexing bypasses the devices.
+    pub fn read(&mut self, address: u16, console: &mut dyn Console) -> io::Result<u16> {
+        match address {
+            KBSR => self.poll_keyboard(console),
+            KBDR => {
+                self[KBSR] &= !STATUS_READY;
+                Ok(self[KBDR])
+            }
+            DSR => Ok(STATUS_READY),
+            _ => Ok(self[address]),
+        }
+    }
+
+    /// KBSR and KBDR as `restore_keyboard` expects them.
+    pub fn keyboard(&self) -> [u16; 2] {
+        [self[KBSR], self[KBDR]]
+    }
+
+    /// Puts back keyboard registers saved by `keyboard`. A character that
+    /// arrived since stays latched, since the console cannot take it back.
+    pub fn restore_keyboard(&mut self, [kbsr, kbdr]: [u16; 2]) {
+        if kbsr & STATUS_READY == 0 && self[KBSR] & STATUS_READY != 0 {
+            self[KBSR] = kbsr | STATUS_READY;
+        } else {
+            self[KBSR] = kbsr;
+            self[KBDR] = kbdr;
+        }
+    }
+
+    /// Returns the vector and priority of a device that is requesting an
+    /// interrupt, if any. Input that has ended never requests one; only an
+    /// explicit read of the keyboard reports it.
+    pub fn pending_interrupt(&mut self, console: &mut dyn Console) -> io::Result<Option<(u8, u8)>> {
+        if self[KBSR] & KBSR_INTERRUPT_ENABLE == 0 {
+            return Ok(None);
+        }
+        let ready = match self.poll_keyboard(console) {
+            Ok(kbsr) => kbsr & STATUS_READY != 0,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(err) => return Err(err),
+        };
+        if ready {
+            return Ok(Some((
+                KEYBOARD_INTERRUPT_VECTOR,
+                KEYBOARD_INTERRUPT_PRIORITY,
+            )));
+        }
+        Ok(None)
+    }
+
+    fn poll_keyboard(&mut self, console: &mut dyn Console) -> io::Result<u16> {
+        if self[KBSR] & STATUS_READY == 0 {
+            if let Some(byte) = console.poll_byte()? {
+                self[KBDR] = byte as u16;
+                self[KBSR] |= STATUS_READY;
+            }
+        }
+        Ok(self[KBSR])
+    }
+
+    /// Writes a word as the CPU sees it, routing device registers to their
+    /// handlers. Plain indexing bypasses the devices.
+    pub fn write(&mut self, address: u16, value: u16, console: &mut dyn Console) -> io::Result<()> {
+        match address {
+            KBSR => {
+                let ready = self[KBSR] & STATUS_READY;
+                self[KBSR] = ready | (value & KBSR_INTERRUPT_ENABLE);
+            }
+            KBDR | DSR => {}
+            DDR => {
+                self[DDR] = value;
+                console.write_byte(value as u8)?;
+                console.flush()?;
+            }
+            _ => self[address] = value,
+        }
+        Ok(())
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Index<u16> for Memory {
@@ -28,3 +174,106 @@ impl IndexMut<u16> for Memory {
         &mut self.0[index as usize]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::{BufferConsole, StreamConsole};
+
+    #[test]
+    fn keyboard_status_reflects_console_input() {
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::new();
+
+        assert_eq!(memory.read(KBSR, &mut console).unwrap(), 0);
+
+        console.push_input(b"ab");
+        assert_eq!(memory.read(KBSR, &mut console).unwrap(), STATUS_READY);
+        assert_eq!(memory.read(KBSR, &mut console).unwrap(), STATUS_READY);
+        assert_eq!(memory.read(KBDR, &mut console).unwrap(), 'a' as u16);
+        assert_eq!(memory[KBSR], 0);
+
+        assert_eq!(memory.read(KBSR, &mut console).unwrap(), STATUS_READY);
+        assert_eq!(memory.read(KBDR, &mut console).unwrap(), 'b' as u16);
+        assert_eq!(memory.read(KBSR, &mut console).unwrap(), 0);
+    }
+
+    #[test]
+    fn keyboard_status_only_accepts_interrupt_enable() {
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::new();
+
+        memory.write(KBSR, 0xFFFF, &mut console).unwrap();
+        assert_eq!(memory[KBSR], KBSR_INTERRUPT_ENABLE);
+    }
+
+    #[test]
+    fn keyboard_requests_interrupt_only_when_enabled() {
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::with_input(b"k");
+
+        assert_eq!(memory.pending_interrupt(&mut console).unwrap(), None);
+        assert_eq!(console.poll_byte().unwrap(), Some(b'k'));
+
+        console.push_input(b"k");
+        memory
+            .write(KBSR, KBSR_INTERRUPT_ENABLE, &mut console)
+            .unwrap();
+        assert_eq!(
+            memory.pending_interrupt(&mut console).unwrap(),
+            Some((KEYBOARD_INTERRUPT_VECTOR, KEYBOARD_INTERRUPT_PRIORITY))
+        );
+
+        assert_eq!(memory.read(KBDR, &mut console).unwrap(), 'k' as u16);
+        assert_eq!(memory.pending_interrupt(&mut console).unwrap(), None);
+    }
+
+    #[test]
+    fn ended_input_requests_no_interrupt() {
+        let mut memory = Memory::new();
+        let mut console = StreamConsole::new(io::empty(), io::sink());
+
+        memory
+            .write(KBSR, KBSR_INTERRUPT_ENABLE, &mut console)
+            .unwrap();
+        assert_eq!(memory.pending_interrupt(&mut console).unwrap(), None);
+        assert_eq!(
+            memory.read(KBSR, &mut console).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn display_writes_reach_console() {
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::new();
+
+        assert_eq!(memory.read(DSR, &mut console).unwrap(), STATUS_READY);
+        memory.write(DDR, 'x' as u16, &mut console).unwrap();
+        assert_eq!(console.output(), b"x");
+    }
+
+    #[test]
+    fn clock_runs_until_mcr_bit_cleared() {
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::new();
+
+        assert!(memory.clock_enabled());
+        memory.write(MCR, 0x7FFF, &mut console).unwrap();
+        assert!(!memory.clock_enabled());
+        memory.set_clock_enabled(true);
+        assert_eq!(memory[MCR], 0xFFFF);
+    }
+
+    #[test]
+    fn ordinary_addresses_are_plain_storage() {
+        let mut memory = Memory::new();
+        let mut console = BufferConsole::new();
+
+        memory.write(0x4000, 0x1234, &mut console).unwrap();
+        assert_eq!(memory.read(0x4000, &mut console).unwrap(), 0x1234);
+        memory.write(0xFE10, 7, &mut console).unwrap();
+        assert_eq!(memory[0xFE10], 7);
+        assert!(console.output().is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+; Minimal LC-3 operating system bundled with the VM.
+;
+; Provides the trap vector table, the interrupt vector table, the standard
+; GETC/OUT/PUTS/IN/PUTSP/HALT service routines and handlers for the
+; privilege, illegal opcode and access control exceptions. Service routines
+; run in supervisor mode, keep every register except their outputs and R7,
+; and return with RTI. Unused table entries are left zero here and pointed at
+; BAD_TRAP / BAD_INT when the image is built.
+
+        .ORIG x0000
+
+; Trap vector table (x0000-x00FF)
+        .BLKW x20
+        .FILL TRAP_GETC         ; x20
+        .FILL TRAP_OUT          ; x21
+        .FILL TRAP_PUTS         ; x22
+        .FILL TRAP_IN           ; x23
+        .FILL TRAP_PUTSP        ; x24
+        .FILL TRAP_HALT         ; x25
+        .BLKW xDA
+
+; Interrupt vector table (x0100-x01FF)
+        .FILL PRIV_HANDLER      ; x00 privilege mode violation
+        .FILL ILLEGAL_HANDLER   ; x01 illegal opcode
+        .FILL ACV_HANDLER       ; x02 access control violation
+        .BLKW xFD
+
+; Service routines (x0200-)
+
+; GETC: read one character from the keyboard into R0.
+TRAP_GETC
+        ADD R6, R6, #-1
+        STR R1, R6, #0
+GETC_POLL
+        LDI R1, OS_KBSR
+        BRzp GETC_POLL
+        LDI R0, OS_KBDR
+        LDR R1, R6, #0
+        ADD R6, R6, #1
+        RTI
+
+; OUT: write the character in R0 to the display.
+TRAP_OUT
+        ADD R6, R6, #-1
+        STR R1, R6, #0
+OUT_POLL
+        LDI R1, OS_DSR
+        BRzp OUT_POLL
+        STI R0, OS_DDR
+        LDR R1, R6, #0
+        ADD R6, R6, #1
+        RTI
+
+; PUTS: write the zero-terminated string at R0, one character per word.
+TRAP_PUTS
+        ADD R6, R6, #-1
+        STR R7, R6, #0
+        JSR OS_PRINT
+        LDR R7, R6, #0
+        ADD R6, R6, #1
+        RTI
+
+; IN: prompt for a character, read it into R0 and echo it followed by a
+; newline.
+TRAP_IN
+        ADD R6, R6, #-2
+        STR R1, R6, #0
+        STR R7, R6, #1
+        LEA R0, IN_PROMPT
+        JSR OS_PRINT
+IN_POLL
+        LDI R1, OS_KBSR
+        BRzp IN_POLL
+        LDI R0, OS_KBDR
+IN_ECHO_POLL
+        LDI R1, OS_DSR
+        BRzp IN_ECHO_POLL
+        STI R0, OS_DDR
+        LD R1, NEWLINE
+IN_NEWLINE_POLL
+        LDI R7, OS_DSR
+        BRzp IN_NEWLINE_POLL
+        STI R1, OS_DDR
+        LDR R7, R6, #1
+        LDR R1, R6, #0
+        ADD R6, R6, #2
+        RTI
+
+; PUTSP: write the zero-terminated string at R0, two characters per word
+; with the low byte first.
+TRAP_PUTSP
+        ADD R6, R6, #-4
+        STR R0, R6, #0
+        STR R1, R6, #1
+        STR R2, R6, #2
+        STR R3, R6, #3
+PUTSP_LOOP
+        LDR R1, R0, #0
+        BRz PUTSP_DONE
+        LD R2, LOW_BYTE
+        AND R2, R1, R2
+PUTSP_POLL_LOW
+        LDI R3, OS_DSR
+        BRzp PUTSP_POLL_LOW
+        STI R2, OS_DDR
+        ; shift the high byte down into R2
+        AND R2, R2, #0
+        AND R3, R3, #0
+        ADD R3, R3, #8
+PUTSP_SHIFT
+        ADD R2, R2, R2
+        ADD R1, R1, #0
+        BRzp PUTSP_SHIFTED
+        ADD R2, R2, #1
+PUTSP_SHIFTED
+        ADD R1, R1, R1
+        ADD R3, R3, #-1
+        BRp PUTSP_SHIFT
+        ADD R2, R2, #0
+        BRz PUTSP_NEXT
+PUTSP_POLL_HIGH
+        LDI R3, OS_DSR
+        BRzp PUTSP_POLL_HIGH
+        STI R2, OS_DDR
+PUTSP_NEXT
+        ADD R0, R0, #1
+        BRnzp PUTSP_LOOP
+PUTSP_DONE
+        LDR R3, R6, #3
+        LDR R2, R6, #2
+        LDR R1, R6, #1
+        LDR R0, R6, #0
+        ADD R6, R6, #4
+        RTI
+
+; HALT: print the halt message and stop the clock. If the clock is started
+; again, execution resumes after the TRAP.
+TRAP_HALT
+        ADD R6, R6, #-2
+        STR R0, R6, #0
+        STR R7, R6, #1
+        LEA R0, HALT_MSG
+        JSR OS_PRINT
+        LDR R0, R6, #0          ; the caller's R0 is visible while stopped
+        JSR OS_STOP_CLOCK
+        LDR R7, R6, #1
+        ADD R6, R6, #2
+        RTI
+
+; Exception handlers report the fault, set bit 14 of the machine control
+; register and stop the machine.
+PRIV_HANDLER
+        LEA R0, PRIV_MSG
+        BRnzp OS_SHUTDOWN
+ILLEGAL_HANDLER
+        LEA R0, ILLEGAL_MSG
+        BRnzp OS_SHUTDOWN
+ACV_HANDLER
+        LEA R0, ACV_MSG
+        BRnzp OS_SHUTDOWN
+BAD_TRAP
+        LEA R0, BAD_TRAP_MSG
+        BRnzp OS_SHUTDOWN
+BAD_INT
+        LEA R0, BAD_INT_MSG
+OS_SHUTDOWN
+        JSR OS_PRINT
+        LDI R1, OS_MCR          ; set the fault bit so the host sees the crash
+        LD R2, FAULT_MASK
+        AND R1, R1, R2
+        LD R2, FAULT_BIT
+        ADD R1, R1, R2
+        STI R1, OS_MCR
+OS_HANG
+        JSR OS_STOP_CLOCK
+        BRnzp OS_HANG
+
+; Write the zero-terminated string at R0 to the display. Keeps R0-R2.
+OS_PRINT
+        ADD R6, R6, #-3
+        STR R0, R6, #0
+        STR R1, R6, #1
+        STR R2, R6, #2
+PRINT_LOOP
+        LDR R1, R0, #0
+        BRz PRINT_DONE
+PRINT_POLL
+        LDI R2, OS_DSR
+        BRzp PRINT_POLL
+        STI R1, OS_DDR
+        ADD R0, R0, #1
+        BRnzp PRINT_LOOP
+PRINT_DONE
+        LDR R2, R6, #2
+        LDR R1, R6, #1
+        LDR R0, R6, #0
+        ADD R6, R6, #3
+        RET
+
+; Clear bit 15 of the machine control register. Keeps R1 and R2.
+OS_STOP_CLOCK
+        ADD R6, R6, #-2
+        STR R1, R6, #0
+        STR R2, R6, #1
+        LDI R1, OS_MCR
+        LD R2, CLOCK_MASK
+        AND R1, R1, R2
+        STI R1, OS_MCR
+        LDR R2, R6, #1
+        LDR R1, R6, #0
+        ADD R6, R6, #2
+        RET
+
+OS_KBSR     .FILL xFE00
+OS_KBDR     .FILL xFE02
+OS_DSR      .FILL xFE04
+OS_DDR      .FILL xFE06
+OS_MCR      .FILL xFFFE
+LOW_BYTE    .FILL x00FF
+NEWLINE     .FILL x000A
+CLOCK_MASK  .FILL x7FFF
+FAULT_MASK  .FILL xBFFF
+FAULT_BIT   .FILL x4000
+
+IN_PROMPT       .STRINGZ "Enter a character: "
+HALT_MSG        .STRINGZ "\n--- Halting the LC-3 ---\n"
+PRIV_MSG        .STRINGZ "\n--- Privilege mode violation ---\n"
+ILLEGAL_MSG     .STRINGZ "\n--- Illegal opcode ---\n"
+ACV_MSG         .STRINGZ "\n--- Access control violation ---\n"
+BAD_TRAP_MSG    .STRINGZ "\n--- Undefined trap executed ---\n"
+BAD_INT_MSG     .STRINGZ "\n--- Unexpected interrupt ---\n"
+
+        .END
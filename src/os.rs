@@ -0,0 +1,263 @@
+use std::{ops::Range, sync::OnceLock};
+
+use crate::{
+    asm::{self, Program},
+    cpu::{Cpu, ExceptionMode, Register, TrapMode, INTERRUPT_VECTOR_TABLE, TRAP_VECTOR_TABLE},
+    memory::{Memory, USER_SPACE_START},
+};
+
+const SOURCE: &str = include_str!("os.asm");
+const TABLE_SIZE: u16 = 0x100;
+
+/// Initial supervisor stack pointer. The stack grows down from just below
+/// user space.
+pub const SUPERVISOR_STACK: u16 = USER_SPACE_START;
+
+/// The bundled operating system, assembled on first use. Its symbols
+/// (`TRAP_HALT`, `HALT_MSG`, ...) locate the individual routines.
+pub fn program() -> &'static Program {
+    static PROGRAM: OnceLock<Program> = OnceLock::new();
+    PROGRAM.get_or_init(|| asm::assemble(SOURCE).expect("bundled OS image must assemble"))
+}
+
+/// Addresses of the service routines and their data, which follow the
+/// vector tables. Images loaded next to the OS may replace vector entries
+/// but not these.
+pub fn routines() -> Range<usize> {
+    let image = program().image();
+    let start = (INTERRUPT_VECTOR_TABLE + TABLE_SIZE) as usize;
+    start..image.origin() as usize + image.words().len()
+}
+
+/// Copies the operating system into `memory`, pointing every unused trap
+/// and interrupt vector at a handler that reports it and stops the machine.
+pub fn load_into(memory: &mut Memory) {
+    let program = program();
+    program.image().load_into(memory);
+
+    let tables = [
+        (TRAP_VECTOR_TABLE, "BAD_TRAP"),
+        (INTERRUPT_VECTOR_TABLE, "BAD_INT"),
+    ];
+    for (base, label) in tables {
+        let handler = program
+            .symbol(label)
+            .expect("bundled OS defines its fallbacks");
+        for entry in base..base + TABLE_SIZE {
+            if memory[entry] == 0 {
+                memory[entry] = handler;
+            }
+        }
+    }
+}
+
+/// Blanks the message the HALT routine in `memory` prints.
+pub fn quiet_halt(memory: &mut Memory) {
+    let message = program()
+        .symbol("HALT_MSG")
+        .expect("bundled OS defines its halt message");
+    memory[message] = 0;
+}
+
+/// Prepares `cpu` to run a user program at `entry` on top of the bundled
+/// operating system: traps and exceptions go through the vector tables and
+/// the supervisor stack starts at `SUPERVISOR_STACK`.
+pub fn boot(cpu: &mut Cpu, entry: u16) {
+    cpu.set_trap_mode(TrapMode::Memory);
+    cpu.set_exception_mode(ExceptionMode::Vector);
+    if cpu.user_mode() {
+        cpu.set_user_mode(false);
+    }
+    cpu[Register::R6] = SUPERVISOR_STACK;
+    cpu.set_user_mode(true);
+    cpu[Register::PC] = entry;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::assemble,
+        console::BufferConsole,
+        cpu::ExitReason,
+        loader::{ImageSet, ObjectImage},
+    };
+
+    fn run_program(source: &str, input: &[u8]) -> (Cpu, Memory, BufferConsole, ExitReason) {
+        let console = BufferConsole::with_input(input);
+        let mut cpu = Cpu::with_console(Box::new(console.clone()));
+        let mut memory = Memory::with_os();
+
+        let program = assemble(source).unwrap();
+        program.image().load_into(&mut memory);
+        boot(&mut cpu, program.image().origin());
+
+        let exit = cpu.execute(&mut memory).unwrap();
+        (cpu, memory, console, exit)
+    }
+
+    #[test]
+    fn every_vector_has_a_handler() {
+        let memory = Memory::with_os();
+        for entry in 0..2 * TABLE_SIZE {
+            assert_ne!(memory[entry], 0, "vector table entry {:#06x}", entry);
+        }
+        assert_eq!(memory[0x0025], program().symbol("TRAP_HALT").unwrap());
+    }
+
+    #[test]
+    fn images_may_replace_vectors_but_not_routines() {
+        let mut images = ImageSet::new();
+        images.reserve("the OS", routines());
+        let err = images
+            .add("low.obj", ObjectImage::new(0x01FF, vec![0; 2]).unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "low.obj overlaps the OS at 0x0200..=0x0200"
+        );
+
+        // TRAP x26; HALT, with a routine at x4000 doing ADD R3, R3, #5; RTI
+        images
+            .add(
+                "main.obj",
+                ObjectImage::new(0x3000, vec![0xF026, 0xF025]).unwrap(),
+            )
+            .unwrap();
+        images
+            .add("vec.obj", ObjectImage::new(0x0026, vec![0x4000]).unwrap())
+            .unwrap();
+        images
+            .add(
+                "trap.obj",
+                ObjectImage::new(0x4000, vec![0x16E5, 0x8000]).unwrap(),
+            )
+            .unwrap();
+
+        let mut cpu = Cpu::with_console(Box::new(BufferConsole::new()));
+        let mut memory = Memory::with_os();
+        images.load_into(&mut memory);
+        boot(&mut cpu, 0x3000);
+
+        assert_eq!(cpu.execute(&mut memory).unwrap(), ExitReason::PoweredOff);
+        assert_eq!(cpu[Register::R3], 5);
+    }
+
+    #[test]
+    fn output_routines_print_through_the_display() {
+        let (cpu, _, console, exit) = run_program(
+            ".ORIG x3000
+            LD R0, CHAR
+            OUT
+            LEA R0, TEXT
+            PUTS
+            LEA R0, PACKED
+            PUTSP
+            HALT
+            CHAR   .FILL x3E
+            TEXT   .STRINGZ \"hi\"
+            PACKED .FILL x6261
+                   .FILL x0063
+                   .FILL 0
+            .END",
+            b"",
+        );
+
+        assert_eq!(exit, ExitReason::PoweredOff);
+        assert_eq!(console.output(), b">hiabc\n--- Halting the LC-3 ---\n");
+        assert!(!cpu.user_mode());
+    }
+
+    #[test]
+    fn halt_keeps_r0_and_can_be_silenced() {
+        let console = BufferConsole::new();
+        let mut cpu = Cpu::with_console(Box::new(console.clone()));
+        let mut memory = Memory::with_os();
+        quiet_halt(&mut memory);
+        // AND R0, R0, #0; ADD R0, R0, #7; HALT
+        memory.write_at(&[0x5020, 0x1027, 0xF025], 0x3000);
+        boot(&mut cpu, 0x3000);
+
+        assert_eq!(cpu.execute(&mut memory).unwrap(), ExitReason::PoweredOff);
+        assert!(!memory.faulted());
+        assert_eq!(cpu[Register::R0], 7);
+        assert!(console.output().is_empty());
+    }
+
+    #[test]
+    fn input_routines_read_the_keyboard() {
+        let (_, memory, console, _) = run_program(
+            ".ORIG x3000
+            GETC
+            ST R0, FIRST
+            IN
+            ST R0, SECOND
+            HALT
+            FIRST  .BLKW 1
+            SECOND .BLKW 1
+            .END",
+            b"xy",
+        );
+
+        assert_eq!(memory[0x3005], 'x' as u16);
+        assert_eq!(memory[0x3006], 'y' as u16);
+        assert!(console
+            .output()
+            .starts_with(b"Enter a character: y\n\n--- Halting"));
+    }
+
+    #[test]
+    fn routines_preserve_registers_and_supervisor_stack() {
+        let (mut cpu, mut memory, _, _) = run_program(
+            ".ORIG x3000
+            LD R1, ONE
+            LD R2, TWO
+            LD R3, THREE
+            LEA R0, TEXT
+            PUTSP
+            PUTS
+            HALT
+            ONE   .FILL 1
+            TWO   .FILL 2
+            THREE .FILL 3
+            TEXT  .FILL x4241
+                  .FILL 0
+            .END",
+            b"",
+        );
+
+        // restart the clock and let HALT return to the program
+        assert!(!memory.clock_enabled());
+        memory.set_clock_enabled(true);
+        while !cpu.user_mode() {
+            cpu.step(&mut memory).unwrap();
+        }
+
+        assert_eq!(cpu[Register::PC], 0x3007);
+        assert_eq!(cpu[Register::R0], 0x300A);
+        assert_eq!(cpu[Register::R1], 1);
+        assert_eq!(cpu[Register::R2], 2);
+        assert_eq!(cpu[Register::R3], 3);
+        assert_eq!(cpu[Register::SSP], SUPERVISOR_STACK);
+    }
+
+    #[test]
+    fn exceptions_and_unknown_traps_stop_the_machine() {
+        let cases = [
+            ("RTI", "Privilege mode violation"),
+            (".FILL xD000", "Illegal opcode"),
+            ("LDI R0, PTR\nPTR .FILL x0200", "Access control violation"),
+            ("TRAP x40", "Undefined trap executed"),
+        ];
+        for (instruction, message) in cases {
+            let source = format!(".ORIG x3000\n{}\n.END", instruction);
+            let (cpu, _, console, exit) = run_program(&source, b"");
+
+            let output = String::from_utf8(console.output()).unwrap();
+            assert_eq!(exit, ExitReason::Faulted);
+            assert_eq!(output, format!("\n--- {} ---\n", message));
+            assert!(!cpu.user_mode());
+        }
+    }
+}